@@ -0,0 +1,190 @@
+#[macro_use]
+extern crate criterion;
+#[macro_use]
+extern crate json_encoder;
+
+use criterion::Criterion;
+use json_encoder::{JsonEncoder, JsonKey};
+use std::hint::black_box;
+
+fn bench_int(c: &mut Criterion) {
+    let mut group = c.benchmark_group("int");
+    let mut js = JsonEncoder::with_capacity(100);
+
+    group.bench_function("encode_i32", |b| b.iter(|| {
+        js.clear();
+        js.encode_i32(black_box(123_456_789));
+        js.encode_i32(black_box(321_654_987));
+        js.encode_i32(black_box(231_564_857));
+        js.encode_i32(black_box(189_123_456));
+    }));
+
+    group.bench_function("encode_u32", |b| b.iter(|| {
+        js.clear();
+        js.encode_u32(black_box(123_456_789));
+        js.encode_u32(black_box(321_654_987));
+        js.encode_u32(black_box(231_564_857));
+        js.encode_u32(black_box(189_123_456));
+    }));
+
+    group.bench_function("encode_u64", |b| b.iter(|| {
+        js.clear();
+        js.encode_u64(black_box(123_456_789));
+        js.encode_u64(black_box(321_654_987));
+        js.encode_u64(black_box(231_564_857));
+        js.encode_u64(black_box(189_123_456));
+    }));
+
+    group.bench_function("encode_u64_large", |b| b.iter(|| {
+        js.clear();
+        js.encode_u64(black_box(12_345_678_901_234_567_890));
+        js.encode_u64(black_box(3_216_549_870_123_456));
+        js.encode_u64(black_box(231_564_857_000_111));
+        js.encode_u64(black_box(1_891_234_567_890));
+    }));
+
+    group.finish();
+}
+
+fn bench_hex(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hex");
+    let mut js = JsonEncoder::with_capacity(80);
+
+    group.bench_function("encode_hex_u32", |b| b.iter(|| {
+        js.clear();
+        js.encode_hex_u32(black_box(123_456_789));
+        js.encode_hex_u32(black_box(321_654_987));
+        js.encode_hex_u32(black_box(231_564_857));
+        js.encode_hex_u32(black_box(189_123_456));
+    }));
+
+    group.bench_function("encode_hex_u32_fast", |b| b.iter(|| {
+        js.clear();
+        js.encode_hex_u32_fast(black_box(123_456_789));
+        js.encode_hex_u32_fast(black_box(321_654_987));
+        js.encode_hex_u32_fast(black_box(231_564_857));
+        js.encode_hex_u32_fast(black_box(189_123_456));
+    }));
+
+    group.finish();
+}
+
+fn bench_f64(c: &mut Criterion) {
+    let mut js = JsonEncoder::with_capacity(120);
+
+    c.bench_function("encode_f64", |b| b.iter(|| {
+        js.clear();
+        js.encode_f64(black_box(1.5));
+        js.encode_f64(black_box(std::f64::consts::PI));
+        js.encode_f64(black_box(-2.2250738585072014e-308));
+        js.encode_f64(black_box(123_456_789.123));
+    }));
+}
+
+const STR: &str = "A string that we want\nto \"escape\". A string that we want\nto \"escape\". A string that we want\nto \"escape\". ";
+
+fn bench_str(c: &mut Criterion) {
+    let mut group = c.benchmark_group("str");
+    let mut js = JsonEncoder::with_capacity(400);
+
+    group.bench_function("encode_str_noescape", |b| b.iter(|| {
+        js.clear();
+        js.encode_str_noescape(black_box(STR));
+    }));
+
+    group.bench_function("encode_str", |b| b.iter(|| {
+        js.clear();
+        js.encode_str(black_box(STR));
+    }));
+
+    group.bench_function("encode_str_short", |b| b.iter(|| {
+        js.clear();
+        js.encode_str(black_box("a \"short\" one"));
+    }));
+
+    group.bench_function("encode_str_", |b| b.iter(|| {
+        js.clear();
+        js.encode_str_(black_box(STR));
+    }));
+
+    group.bench_function("encode_str3", |b| b.iter(|| {
+        js.clear();
+        js.encode_str3(black_box(STR));
+    }));
+
+    group.bench_function("encode_str2", |b| b.iter(|| {
+        js.clear();
+        js.encode_str2(black_box(STR));
+    }));
+
+    group.finish();
+}
+
+fn bench_str_long(c: &mut Criterion) {
+    // mostly long runs of ASCII, where the vectorized scan pays off
+    let clean: String = STR.replace('\n', " ").replace('"', "'").repeat(16);
+    let sparse: String = STR.repeat(16);
+
+    let mut group = c.benchmark_group("str_long");
+    let mut js = JsonEncoder::with_capacity(16 * 1024);
+
+    for &(name, s) in [("clean", &clean), ("sparse", &sparse)].iter() {
+        group.bench_function(format!("encode_str/{}", name), |b| b.iter(|| {
+            js.clear();
+            js.encode_str(black_box(s));
+        }));
+
+        group.bench_function(format!("encode_str_/{}", name), |b| b.iter(|| {
+            js.clear();
+            js.encode_str_(black_box(s));
+        }));
+
+        group.bench_function(format!("encode_str2/{}", name), |b| b.iter(|| {
+            js.clear();
+            js.encode_str2(black_box(s));
+        }));
+    }
+
+    group.finish();
+}
+
+fn bench_field(c: &mut Criterion) {
+    const ID: JsonKey = json_key!("id");
+    const NAME: JsonKey = json_key!("name");
+    const ACTIVE: JsonKey = json_key!("active");
+
+    let mut group = c.benchmark_group("field");
+    let mut js = JsonEncoder::with_capacity(100);
+
+    group.bench_function("encode_field", |b| b.iter(|| {
+        js.clear();
+        js.encode_obj(|jso| {
+            jso.encode_field("id", |js| js.encode_u64(black_box(42)));
+            jso.encode_field("name", |js| js.encode_str(black_box("name")));
+            jso.encode_field("active", |js| js.encode_bool(black_box(true)));
+        });
+    }));
+
+    group.bench_function("encode_field_raw", |b| b.iter(|| {
+        js.clear();
+        js.encode_obj(|jso| {
+            jso.encode_field_raw("id", |js| js.encode_u64(black_box(42)));
+            jso.encode_field_raw("name", |js| js.encode_str(black_box("name")));
+            jso.encode_field_raw("active", |js| js.encode_bool(black_box(true)));
+        });
+    }));
+
+    group.bench_function("encode_key", |b| b.iter(|| {
+        js.clear();
+        js.encode_obj(|jso| {
+            jso.encode_key(ID, |js| js.encode_u64(black_box(42)));
+            jso.encode_key(NAME, |js| js.encode_str(black_box("name")));
+            jso.encode_key(ACTIVE, |js| js.encode_bool(black_box(true)));
+        });
+    }));
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_int, bench_hex, bench_f64, bench_str, bench_str_long, bench_field);
+criterion_main!(benches);
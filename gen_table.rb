@@ -10,6 +10,13 @@ def add(str, val)
     }
 end
 
+# Characters without a short escape are written as \u00XX.
+def add_unicode(str)
+    str.each_char {|c|
+        C[c.ord] ||= 'u'.ord
+    }
+end
+
 def gen_rust
     puts %{const LUT: [u8; 256] = [}
     a = (0..255).map {|c| (C[c] || 0).to_s.rjust(2) + " /* " + c.to_s.rjust(3) + " */"}
@@ -41,6 +48,7 @@ add("\x0c", 'f'.ord)
 add("\n", 'n'.ord)
 add("\r", 'r'.ord)
 add("\t", 't'.ord)
+add_unicode((0...32).map {|c| c.chr}.join)
 
 gen_rust
 gen_rust_bin
@@ -0,0 +1,461 @@
+//! `#[derive(JsonEncodable)]` for the `json-encoder` crate.
+//!
+//! Structs with named fields are encoded as objects, tuple structs as arrays
+//! (newtypes as their only field) and unit structs as `null`. Enums are
+//! externally tagged by default: unit variants become `"Variant"`, all others
+//! `{"Variant":...}`.
+//!
+//! Field and variant names are escaped at compile time, so the generated code
+//! writes each `"name":` prefix with a single `push_all` through a `JsonKey`.
+//!
+//! Field attributes:
+//!
+//! * `#[json(rename = "name")]` encodes the field under a different name.
+//! * `#[json(skip)]` never encodes the field.
+//! * `#[json(skip_if = "path::to::fn")]` skips the field if `fn(&field)`
+//!   returns true.
+//! * `#[json(flatten)]` writes the fields of the field's value into the
+//!   enclosing object. Its type must implement `JsonEncodableFields`.
+//!
+//! Fields of tuple structs and variants only accept `#[json(skip)]`. A
+//! newtype struct or variant whose field is skipped is encoded like a unit.
+//!
+//! Variants accept `#[json(rename = "name")]`.
+//!
+//! The enum representation is selected on the type:
+//!
+//! * `#[json(tag = "t")]` is internally tagged: `{"t":"Variant",...}`.
+//!   Struct variants contribute their fields, newtype variants the fields of
+//!   their value (which must implement `JsonEncodableFields`). Tuple variants
+//!   are rejected.
+//! * `#[json(tag = "t", content = "c")]` is adjacently tagged:
+//!   `{"t":"Variant","c":...}`. Unit variants omit `"c"`.
+//! * `#[json(untagged)]` writes only the content, and `null` for unit
+//!   variants.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+#[macro_use]
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Ident, Literal, Span, TokenStream as TokenStream2};
+use syn::{Attribute, Data, DataEnum, DeriveInput, ExprPath, Fields, FieldsNamed, LitStr};
+
+#[proc_macro_derive(JsonEncodable, attributes(json))]
+pub fn derive_json_encodable(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// How enum variants are represented.
+enum Tagging {
+    External,
+    Internal(String),
+    Adjacent(String, String),
+    Untagged,
+}
+
+fn parse_tagging(input: &DeriveInput) -> syn::Result<Option<Tagging>> {
+    let mut tag = None;
+    let mut content = None;
+    let mut untagged = false;
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("json")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("tag") {
+                let name: LitStr = meta.value()?.parse()?;
+                tag = Some(name.value());
+            } else if meta.path.is_ident("content") {
+                let name: LitStr = meta.value()?.parse()?;
+                content = Some(name.value());
+            } else if meta.path.is_ident("untagged") {
+                untagged = true;
+            } else {
+                return Err(meta.error("unknown json container attribute"));
+            }
+            Ok(())
+        })?;
+    }
+    match (tag, content, untagged) {
+        (None, None, false) => Ok(None),
+        (None, None, true) => Ok(Some(Tagging::Untagged)),
+        (Some(tag), None, false) => Ok(Some(Tagging::Internal(tag))),
+        (Some(tag), Some(content), false) => Ok(Some(Tagging::Adjacent(tag, content))),
+        (None, Some(_), false) => {
+            Err(syn::Error::new_spanned(input, "#[json(content = ...)] requires #[json(tag = ...)]"))
+        }
+        (_, _, true) => {
+            Err(syn::Error::new_spanned(input, "#[json(untagged)] cannot be combined with a tag"))
+        }
+    }
+}
+
+#[derive(Default)]
+struct FieldAttrs {
+    rename: Option<String>,
+    skip: bool,
+    skip_if: Option<ExprPath>,
+    flatten: bool,
+}
+
+fn parse_field_attrs(attrs: &[Attribute]) -> syn::Result<FieldAttrs> {
+    let mut field_attrs = FieldAttrs::default();
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("json")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                let name: LitStr = meta.value()?.parse()?;
+                field_attrs.rename = Some(name.value());
+            } else if meta.path.is_ident("skip") {
+                field_attrs.skip = true;
+            } else if meta.path.is_ident("skip_if") {
+                let path: LitStr = meta.value()?.parse()?;
+                field_attrs.skip_if = Some(path.parse()?);
+            } else if meta.path.is_ident("flatten") {
+                field_attrs.flatten = true;
+            } else {
+                return Err(meta.error("unknown json field attribute"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(field_attrs)
+}
+
+/// Like `parse_field_attrs`, for fields of tuple structs and variants, which
+/// accept only `#[json(skip)]`. Returns whether the field is skipped.
+fn parse_tuple_field_skip(field: &syn::Field) -> syn::Result<bool> {
+    let attrs = parse_field_attrs(&field.attrs)?;
+    if attrs.rename.is_some() || attrs.skip_if.is_some() || attrs.flatten {
+        return Err(syn::Error::new_spanned(field, "tuple fields only accept #[json(skip)]"));
+    }
+    Ok(attrs.skip)
+}
+
+fn parse_variant_rename(attrs: &[Attribute]) -> syn::Result<Option<String>> {
+    let mut rename = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("json")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                let name: LitStr = meta.value()?.parse()?;
+                rename = Some(name.value());
+                Ok(())
+            } else {
+                Err(meta.error("unknown json variant attribute"))
+            }
+        })?;
+    }
+    Ok(rename)
+}
+
+/// Escapes `s` the same way `JsonEncoder::encode_str` does.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\x08' => escaped.push_str("\\b"),
+            '\x0c' => escaped.push_str("\\f"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c < ' ' => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `"name"` as a byte string literal.
+fn quoted(name: &str) -> Literal {
+    Literal::byte_string(format!("\"{}\"", escape(name)).as_bytes())
+}
+
+/// A constant `JsonKey` for `name`, like `json_key!` builds it.
+fn json_key(name: &str) -> TokenStream2 {
+    let with_sep = Literal::byte_string(format!(",\"{}\":", escape(name)).as_bytes());
+    let name_len = name.len();
+    quote! {{
+        const KEY: ::json_encoder::JsonKey = ::json_encoder::JsonKey::from_escaped(#with_sep, #name_len);
+        KEY
+    }}
+}
+
+fn binding(i: usize) -> Ident {
+    Ident::new(&format!("__binding_{}", i), Span::call_site())
+}
+
+/// Pattern binding the i-th field by reference, or `_` if it is skipped.
+fn binding_pattern(i: usize, field: &syn::Field) -> syn::Result<TokenStream2> {
+    if parse_field_attrs(&field.attrs)?.skip {
+        Ok(quote!(_))
+    } else {
+        let binding = binding(i);
+        Ok(quote!(ref #binding))
+    }
+}
+
+/// Statements writing the named `fields` into the object encoder `jso`.
+/// `access[i]` is an expression of type `&FieldType` for the i-th field.
+fn encode_named_fields(fields: &FieldsNamed, access: &[TokenStream2]) -> syn::Result<TokenStream2> {
+    let mut stmts = TokenStream2::new();
+    for (field, access) in fields.named.iter().zip(access.iter()) {
+        let attrs = parse_field_attrs(&field.attrs)?;
+        if attrs.skip {
+            continue;
+        }
+        let stmt = if attrs.flatten {
+            quote! {
+                ::json_encoder::JsonEncodableFields::encode_fields(#access, jso);
+            }
+        } else {
+            let name = match attrs.rename {
+                Some(name) => name,
+                None => unraw(field.ident.as_ref().unwrap()),
+            };
+            let key = json_key(&name);
+            quote! {
+                jso.encode_key(#key, |js| ::json_encoder::JsonEncodable::encode(#access, js));
+            }
+        };
+        match attrs.skip_if {
+            Some(path) => stmts.extend(quote! { if !#path(#access) { #stmt } }),
+            None => stmts.extend(stmt),
+        }
+    }
+    Ok(stmts)
+}
+
+/// Statements writing the unnamed `access` expressions as array elements.
+fn encode_tuple_fields(fields: &syn::FieldsUnnamed, access: &[TokenStream2]) -> syn::Result<TokenStream2> {
+    let mut stmts = TokenStream2::new();
+    for (field, access) in fields.unnamed.iter().zip(access.iter()) {
+        if parse_tuple_field_skip(field)? {
+            continue;
+        }
+        stmts.extend(quote! {
+            jsa.encode_elm(|js| ::json_encoder::JsonEncodable::encode(#access, js));
+        });
+    }
+    Ok(stmts)
+}
+
+fn unraw(ident: &Ident) -> String {
+    let name = ident.to_string();
+    match name.strip_prefix("r#") {
+        Some(name) => name.to_string(),
+        None => name,
+    }
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let mut generics = input.generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(::json_encoder::JsonEncodable));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let tagging = parse_tagging(input)?;
+
+    let body = match input.data {
+        Data::Struct(_) if tagging.is_some() => {
+            return Err(syn::Error::new_spanned(input, "enum tagging attributes are only allowed on enums"));
+        }
+        Data::Struct(ref data) => match data.fields {
+            Fields::Named(ref fields) => {
+                let access: Vec<_> = fields.named.iter().map(|field| {
+                    let ident = field.ident.as_ref().unwrap();
+                    quote!(&self.#ident)
+                }).collect();
+                let stmts = encode_named_fields(fields, &access)?;
+                return Ok(quote! {
+                    impl #impl_generics ::json_encoder::JsonEncodableFields for #name #ty_generics #where_clause {
+                        #[inline]
+                        fn encode_fields<__S: ::json_encoder::Sink>(&self, jso: &mut ::json_encoder::JsonObjectEncoder<__S>) {
+                            #stmts
+                        }
+                    }
+
+                    impl #impl_generics ::json_encoder::JsonEncodable for #name #ty_generics #where_clause {
+                        #[inline]
+                        fn encode<__S: ::json_encoder::Sink>(&self, js: &mut ::json_encoder::JsonEncoder<__S>) {
+                            js.encode_obj(|jso| ::json_encoder::JsonEncodableFields::encode_fields(self, jso));
+                        }
+                    }
+                });
+            }
+            Fields::Unnamed(ref fields) if fields.unnamed.len() == 1 => {
+                if parse_tuple_field_skip(&fields.unnamed[0])? {
+                    quote! { js.encode_null(); }
+                } else {
+                    quote! { ::json_encoder::JsonEncodable::encode(&self.0, js); }
+                }
+            }
+            Fields::Unnamed(ref fields) => {
+                let access: Vec<_> = (0..fields.unnamed.len()).map(|i| {
+                    let index = syn::Index::from(i);
+                    quote!(&self.#index)
+                }).collect();
+                let stmts = encode_tuple_fields(fields, &access)?;
+                quote! { js.encode_array(|jsa| { #stmts }); }
+            }
+            Fields::Unit => quote! { js.encode_null(); },
+        },
+        Data::Enum(ref data) => expand_enum(name, data, tagging.unwrap_or(Tagging::External))?,
+        Data::Union(_) => {
+            return Err(syn::Error::new_spanned(input, "JsonEncodable cannot be derived for unions"));
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::json_encoder::JsonEncodable for #name #ty_generics #where_clause {
+            #[inline]
+            fn encode<__S: ::json_encoder::Sink>(&self, js: &mut ::json_encoder::JsonEncoder<__S>) {
+                #body
+            }
+        }
+    })
+}
+
+fn expand_enum(name: &Ident, data: &DataEnum, tagging: Tagging) -> syn::Result<TokenStream2> {
+    let mut arms = TokenStream2::new();
+    for variant in data.variants.iter() {
+        let ident = &variant.ident;
+        let variant_name = match parse_variant_rename(&variant.attrs)? {
+            Some(name) => name,
+            None => unraw(ident),
+        };
+
+        // the pattern, the expression encoding the variant's content (if any)
+        // and, for struct variants, the statements writing its fields
+        let (pattern, content, field_stmts) = match variant.fields {
+            Fields::Unit => (quote!(#name::#ident), None, None),
+            Fields::Unnamed(ref fields) => {
+                let patterns = fields.unnamed.iter().enumerate()
+                    .map(|(i, field)| binding_pattern(i, field))
+                    .collect::<syn::Result<Vec<_>>>()?;
+                let access: Vec<_> = (0..fields.unnamed.len()).map(|i| {
+                    let binding = binding(i);
+                    quote!(#binding)
+                }).collect();
+                let content = if fields.unnamed.len() == 1 {
+                    if parse_tuple_field_skip(&fields.unnamed[0])? {
+                        quote! { js.encode_null() }
+                    } else {
+                        quote! { ::json_encoder::JsonEncodable::encode(__binding_0, js) }
+                    }
+                } else {
+                    let stmts = encode_tuple_fields(fields, &access)?;
+                    quote! { js.encode_array(|jsa| { #stmts }) }
+                };
+                (quote!(#name::#ident(#(#patterns),*)), Some(content), None)
+            }
+            Fields::Named(ref fields) => {
+                let idents: Vec<_> = fields.named.iter().map(|f| f.ident.as_ref().unwrap()).collect();
+                let patterns = fields.named.iter().enumerate()
+                    .map(|(i, field)| binding_pattern(i, field))
+                    .collect::<syn::Result<Vec<_>>>()?;
+                let access: Vec<_> = (0..idents.len()).map(|i| {
+                    let binding = binding(i);
+                    quote!(#binding)
+                }).collect();
+                let stmts = encode_named_fields(fields, &access)?;
+                let content = quote! { js.encode_obj(|jso| { #stmts }) };
+                (quote!(#name::#ident { #(#idents: #patterns),* }), Some(content), Some(stmts))
+            }
+        };
+
+        let body = match tagging {
+            Tagging::External => match content {
+                None => {
+                    let quoted_name = quoted(&variant_name);
+                    quote! { js.encode_str_preescaped(#quoted_name) }
+                }
+                Some(content) => {
+                    let key = json_key(&variant_name);
+                    quote! {
+                        js.encode_obj(|jso| {
+                            jso.encode_key(#key, |js| #content)
+                        })
+                    }
+                }
+            },
+            Tagging::Internal(ref tag) => {
+                let tag = json_key(tag);
+                let fields = match (field_stmts, content) {
+                    (Some(stmts), _) => stmts,
+                    (None, None) => TokenStream2::new(),
+                    (None, Some(_)) => {
+                        let mut skipped = false;
+                        if let Fields::Unnamed(ref fields) = variant.fields {
+                            if fields.unnamed.len() != 1 {
+                                return Err(syn::Error::new_spanned(variant,
+                                    "internally tagged enums cannot contain tuple variants"));
+                            }
+                            skipped = parse_tuple_field_skip(&fields.unnamed[0])?;
+                        }
+                        if skipped {
+                            TokenStream2::new()
+                        } else {
+                            quote! { ::json_encoder::JsonEncodableFields::encode_fields(__binding_0, jso); }
+                        }
+                    }
+                };
+                quote! {
+                    js.encode_obj(|jso| {
+                        jso.encode_key(#tag, |js| js.encode_str(#variant_name));
+                        #fields
+                    })
+                }
+            }
+            Tagging::Adjacent(ref tag, ref content_key) => {
+                let tag = json_key(tag);
+                let content = content.map(|content| {
+                    let key = json_key(content_key);
+                    quote! { jso.encode_key(#key, |js| #content); }
+                });
+                quote! {
+                    js.encode_obj(|jso| {
+                        jso.encode_key(#tag, |js| js.encode_str(#variant_name));
+                        #content
+                    })
+                }
+            }
+            Tagging::Untagged => match content {
+                None => quote! { js.encode_null() },
+                Some(content) => content,
+            },
+        };
+        arms.extend(quote! { #pattern => #body, });
+    }
+
+    Ok(quote! {
+        match *self {
+            #arms
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::expand;
+
+    fn expand_err(input: &str) -> String {
+        expand(&syn::parse_str(input).unwrap()).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_tuple_field_attrs() {
+        let msg = "tuple fields only accept #[json(skip)]";
+        assert_eq!(msg, expand_err("struct A(#[json(rename = \"x\")] u32);"));
+        assert_eq!(msg, expand_err("struct A(u32, #[json(flatten)] B);"));
+        assert_eq!(msg, expand_err("enum A { B(#[json(skip_if = \"f\")] u32) }"));
+        assert_eq!(msg, expand_err("enum A { B(u32, #[json(rename = \"x\")] u32) }"));
+        assert!(expand(&syn::parse_str("struct A(#[json(skip)] u32, u32);").unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,196 @@
+extern crate json_encoder;
+
+use std::collections::BTreeMap;
+use json_encoder::{JsonEncodable, JsonEncoder, NonAscii};
+
+fn encode<T: JsonEncodable>(val: &T) -> String {
+    let mut js = JsonEncoder::new();
+    val.encode(&mut js);
+    String::from_utf8(js.into_vec()).unwrap()
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+#[derive(JsonEncodable)]
+struct Inner {
+    a: u32,
+    b: &'static str,
+}
+
+#[derive(JsonEncodable)]
+struct Outer {
+    id: u64,
+    #[json(rename = "the \"name\"")]
+    name: String,
+    #[json(skip)]
+    #[allow(dead_code)]
+    secret: String,
+    #[json(skip_if = "is_zero")]
+    count: u32,
+    #[json(flatten)]
+    inner: Inner,
+    #[json(flatten)]
+    extra: BTreeMap<String, bool>,
+    r#type: Option<Vec<i32>>,
+}
+
+#[derive(JsonEncodable)]
+struct Newtype(i32);
+
+#[derive(JsonEncodable)]
+struct Pair(&'static str, f64);
+
+#[derive(JsonEncodable)]
+struct Unit;
+
+#[derive(JsonEncodable)]
+struct Generic<T> {
+    value: T,
+}
+
+#[derive(JsonEncodable)]
+enum Shape {
+    Empty,
+    #[json(rename = "circle")]
+    Circle(f64),
+    Point(i32, i32),
+    Rect { w: u32, #[json(skip)] _cache: u32, h: u32 },
+}
+
+#[test]
+fn test_derive_struct() {
+    let mut extra = BTreeMap::new();
+    extra.insert("x".to_string(), true);
+
+    let outer = Outer {
+        id: 1,
+        name: "n".to_string(),
+        secret: "s".to_string(),
+        count: 0,
+        inner: Inner { a: 2, b: "b" },
+        extra,
+        r#type: None,
+    };
+    assert_eq!("{\"id\":1,\"the \\\"name\\\"\":\"n\",\"a\":2,\"b\":\"b\",\"x\":true,\"type\":null}", encode(&outer));
+
+    let outer = Outer { count: 3, r#type: Some(vec![1]), extra: BTreeMap::new(), ..outer };
+    assert_eq!("{\"id\":1,\"the \\\"name\\\"\":\"n\",\"count\":3,\"a\":2,\"b\":\"b\",\"type\":[1]}", encode(&outer));
+}
+
+#[test]
+fn test_derive_tuple_and_unit_structs() {
+    assert_eq!("-5", encode(&Newtype(-5)));
+    assert_eq!("[\"a\",0.5]", encode(&Pair("a", 0.5)));
+    assert_eq!("null", encode(&Unit));
+    assert_eq!("{\"value\":[true]}", encode(&Generic { value: vec![true] }));
+}
+
+#[test]
+fn test_derive_enum() {
+    assert_eq!("\"Empty\"", encode(&Shape::Empty));
+    assert_eq!("{\"circle\":1.5}", encode(&Shape::Circle(1.5)));
+    assert_eq!("{\"Point\":[1,-2]}", encode(&Shape::Point(1, -2)));
+    assert_eq!("{\"Rect\":{\"w\":3,\"h\":4}}", encode(&Shape::Rect { w: 3, _cache: 0, h: 4 }));
+}
+
+#[derive(JsonEncodable)]
+#[json(tag = "type")]
+enum Internal {
+    Unit,
+    Struct { a: u32 },
+    Newtype(Inner),
+}
+
+#[derive(JsonEncodable)]
+#[json(tag = "t", content = "c")]
+enum Adjacent {
+    Unit,
+    Newtype(bool),
+    Tuple(u32, u32),
+    #[json(rename = "S")]
+    Struct { a: u32 },
+}
+
+#[derive(JsonEncodable)]
+#[json(untagged)]
+enum Untagged {
+    Unit,
+    Newtype(&'static str),
+    Tuple(u32, u32),
+    Struct { a: u32 },
+}
+
+#[test]
+fn test_derive_internally_tagged() {
+    assert_eq!("{\"type\":\"Unit\"}", encode(&Internal::Unit));
+    assert_eq!("{\"type\":\"Struct\",\"a\":1}", encode(&Internal::Struct { a: 1 }));
+    assert_eq!("{\"type\":\"Newtype\",\"a\":2,\"b\":\"x\"}", encode(&Internal::Newtype(Inner { a: 2, b: "x" })));
+}
+
+#[test]
+fn test_derive_adjacently_tagged() {
+    assert_eq!("{\"t\":\"Unit\"}", encode(&Adjacent::Unit));
+    assert_eq!("{\"t\":\"Newtype\",\"c\":true}", encode(&Adjacent::Newtype(true)));
+    assert_eq!("{\"t\":\"Tuple\",\"c\":[1,2]}", encode(&Adjacent::Tuple(1, 2)));
+    assert_eq!("{\"t\":\"S\",\"c\":{\"a\":3}}", encode(&Adjacent::Struct { a: 3 }));
+}
+
+#[test]
+fn test_derive_untagged() {
+    assert_eq!("null", encode(&Untagged::Unit));
+    assert_eq!("\"s\"", encode(&Untagged::Newtype("s")));
+    assert_eq!("[1,2]", encode(&Untagged::Tuple(1, 2)));
+    assert_eq!("{\"a\":3}", encode(&Untagged::Struct { a: 3 }));
+}
+
+#[derive(JsonEncodable)]
+enum Accented {
+    #[json(rename = "caf\u{e9}")]
+    Cafe,
+    #[json(rename = "cr\u{e8}me")]
+    Creme { #[json(rename = "cl\u{e9}")] key: u32 },
+}
+
+#[test]
+fn test_derive_non_ascii() {
+    let encode_ascii = |val: &Accented| {
+        let mut js = JsonEncoder::new();
+        js.set_non_ascii(NonAscii::EscapeLower);
+        val.encode(&mut js);
+        String::from_utf8(js.into_vec()).unwrap()
+    };
+    assert_eq!("\"caf\u{e9}\"", encode(&Accented::Cafe));
+    assert_eq!("{\"cr\u{e8}me\":{\"cl\u{e9}\":1}}", encode(&Accented::Creme { key: 1 }));
+    assert_eq!("\"caf\\u00e9\"", encode_ascii(&Accented::Cafe));
+    assert_eq!("{\"cr\\u00e8me\":{\"cl\\u00e9\":1}}", encode_ascii(&Accented::Creme { key: 1 }));
+}
+
+#[derive(JsonEncodable)]
+struct SkippedNewtype(#[json(skip)] #[allow(dead_code)] u32);
+
+#[derive(JsonEncodable)]
+enum SkippedVariant {
+    Newtype(#[json(skip)] #[allow(dead_code)] u32),
+}
+
+#[derive(JsonEncodable)]
+#[json(tag = "type")]
+enum SkippedInternal {
+    Newtype(#[json(skip)] #[allow(dead_code)] Inner),
+}
+
+#[derive(JsonEncodable)]
+#[json(tag = "t", content = "c")]
+enum SkippedAdjacent {
+    Newtype(#[json(skip)] #[allow(dead_code)] u32),
+}
+
+#[test]
+fn test_derive_skipped_single_field() {
+    assert_eq!("null", encode(&SkippedNewtype(1)));
+    assert_eq!("{\"Newtype\":null}", encode(&SkippedVariant::Newtype(1)));
+    assert_eq!("{\"type\":\"Newtype\"}", encode(&SkippedInternal::Newtype(Inner { a: 1, b: "b" })));
+    assert_eq!("{\"t\":\"Newtype\",\"c\":null}", encode(&SkippedAdjacent::Newtype(1)));
+}
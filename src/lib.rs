@@ -1,63 +1,196 @@
-#![feature(test)]
-#![feature(link_llvm_intrinsics)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+// written in the explicit style of Rust 2015
+#![allow(clippy::redundant_field_names, clippy::redundant_static_lifetimes,
+         clippy::needless_lifetimes, clippy::ptr_offset_with_cast,
+         clippy::assign_op_pattern, clippy::identity_op)]
 
-#[cfg(test)]
-extern crate test;
+#[cfg(any(feature = "std", test))]
+extern crate core;
+
+extern crate alloc;
+extern crate ryu;
+
+#[cfg(feature = "bytes")]
+extern crate bytes;
+
+#[cfg(feature = "derive")]
+extern crate json_encoder_derive;
+
+#[cfg(feature = "derive")]
+pub use json_encoder_derive::JsonEncodable;
+
+#[cfg(feature = "serde")]
+extern crate serde;
+
+#[cfg(feature = "serde")]
+pub mod ser;
+
+pub mod sink;
 
-extern crate vec_byte_appender;
+#[cfg(target_arch = "x86_64")]
+mod simd;
 
-use std::ptr;
-use vec_byte_appender::{append_bytes_uninit, append_bytes_uninit_flex};
+pub use sink::{ArraySink, FmtSink, Sink, SliceSink};
 
-extern {
-    #[link_name = "llvm.expect.i64"]
-    fn expect_u64(val: u64, expected_val: u64) -> u64;
+#[cfg(feature = "std")]
+pub use sink::IoSink;
 
-    #[link_name = "llvm.expect.i8"]
-    fn expect_u8(val: u8, expected_val: u8) -> u8;
+use core::{error, fmt, ptr, slice, str};
+use core::cell::Cell;
+use core::mem::MaybeUninit;
+use alloc::borrow::{Cow, ToOwned};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::hash::{BuildHasher, Hash};
+#[cfg(feature = "std")]
+use std::io;
+
+// Calling a `#[cold]` function on a path makes LLVM lay it out as the
+// unlikely one, like `llvm.expect` does.
+#[cold]
+#[inline]
+fn cold() {}
+
+#[inline(always)]
+fn likely(b: bool) -> bool {
+    if !b {
+        cold();
+    }
+    b
 }
 
-pub struct Buffer {
-   data: Vec<u8>
+pub struct Buffer<S = Vec<u8>> {
+   sink: S,
+   // number of bytes the sink already handed over, see `Sink::drain`
+   flushed: usize,
+   // set when the sink ran out of room
+   full: bool,
+   // writes that would end beyond this position are refused
+   max_len: usize,
+   // set when a write was refused because of `max_len`
+   over_limit: bool,
 }
 
 impl Buffer {
     pub fn new() -> Buffer {
-        Buffer{data: Vec::new()}
+        Buffer::with_sink(Vec::new())
     }
 
     pub fn with_capacity(capa: usize) -> Buffer {
-        Buffer{data: Vec::with_capacity(capa)}
+        Buffer::with_sink(Vec::with_capacity(capa))
+    }
+
+    #[inline]
+    pub fn into_vec(self) -> Vec<u8> {
+        self.sink
     }
+}
+
+impl Default for Buffer {
+    fn default() -> Buffer {
+        Buffer::new()
+    }
+}
 
+impl<S: Sink> Buffer<S> {
+    pub fn with_sink(sink: S) -> Buffer<S> {
+        Buffer{sink: sink, flushed: 0, full: false, max_len: usize::MAX, over_limit: false}
+    }
+
+    /// Positions count all bytes written so far, including drained ones.
     pub fn get_current_position(&self) -> usize {
-        self.data.len()
+        self.flushed + self.sink.len()
     }
 
+    /// Panics if `pos` lies within output the sink already handed over.
     pub fn set_current_position(&mut self, pos: usize) {
-        self.data.truncate(pos);
+        assert!(pos >= self.flushed, "cannot roll back into flushed output");
+        self.sink.truncate(pos - self.flushed);
     }
 
     #[inline]
     pub fn clear(&mut self) {
-        self.data.clear();
+        self.sink.truncate(0);
+        self.flushed = 0;
+        self.full = false;
+        self.over_limit = false;
+    }
+
+    /// Lets the sink hand over its output, see `Sink::drain`.
+    #[inline]
+    pub fn drain(&mut self) -> Result<(), JsonError> {
+        let len = self.sink.len();
+        let result = self.sink.drain();
+        self.flushed += len - self.sink.len();
+        result
+    }
+
+    #[inline(always)]
+    pub fn as_mut_ref(&mut self) -> &mut S {
+        &mut self.sink
+    }
+
+    #[inline]
+    pub fn into_sink(self) -> S {
+        self.sink
+    }
+
+    /// Appends `len` bytes, written by `f` into uninitialized memory.
+    #[inline(always)]
+    pub fn append_uninit<F>(&mut self, len: usize, f: F) where F: FnOnce(&mut [u8]) {
+        if !likely(self.get_current_position() + len <= self.max_len) {
+            self.over_limit = true;
+            return;
+        }
+        if !self.sink.reserve(len) {
+            self.full = true;
+            return;
+        }
+        unsafe {
+            let old_len = self.sink.len();
+            f(slice::from_raw_parts_mut(self.sink.spare_ptr(), len));
+            self.sink.set_len(old_len + len);
+        }
     }
 
+    /// Like `append_uninit`, for at most `max_len` bytes; `f` returns how
+    /// many it wrote. Returns `false` without calling `f` if the sink has no
+    /// room for `max_len` bytes, or if they could exceed the output limit,
+    /// so that the caller can fall back to exact writes.
     #[inline(always)]
-    pub fn as_mut_ref(&mut self) -> &mut Vec<u8> {
-        &mut self.data
+    pub fn append_uninit_flex<F>(&mut self, max_len: usize, f: F) -> bool where F: FnOnce(&mut [u8]) -> usize {
+        if self.get_current_position() + max_len > self.max_len || !self.sink.reserve(max_len) {
+            return false;
+        }
+        unsafe {
+            let old_len = self.sink.len();
+            let len = f(slice::from_raw_parts_mut(self.sink.spare_ptr(), max_len));
+            debug_assert!(len <= max_len);
+            self.sink.set_len(old_len + len);
+        }
+        true
     }
 
     #[inline]
     pub fn push(&mut self, byte: u8) {
-       self.data.push(byte);
+        self.append_uninit(1, |ext| unsafe {
+            ptr::write(ext.as_mut_ptr(), byte);
+        });
     }
 
     #[inline]
     pub fn push_all(&mut self, bytes: &[u8]) {
         let len = bytes.len();
         if len == 0 { return }
-        append_bytes_uninit(&mut self.data, len, |ext| unsafe {
+        self.append_uninit(len, |ext| unsafe {
             ptr::copy_nonoverlapping(bytes.as_ptr(), ext.as_mut_ptr(), len);
         });
     }
@@ -66,7 +199,7 @@ impl Buffer {
     #[inline]
     pub fn push_all_around(&mut self, around: u8, bytes: &[u8]) {
         let len = bytes.len();
-        append_bytes_uninit(&mut self.data, len + 2, |ext| unsafe {
+        self.append_uninit(len + 2, |ext| unsafe {
             ptr::write(ext.as_mut_ptr(), around);
             ptr::copy_nonoverlapping(bytes.as_ptr(),
                                      ext.as_mut_ptr().offset(1),
@@ -78,7 +211,7 @@ impl Buffer {
     // XXX: If before, after, bytes.len() > isize::max, this fails.
     #[inline]
     pub fn push_all_around2(&mut self, before: &[u8], bytes: &[u8], after: &[u8]) {
-        append_bytes_uninit(&mut self.data, before.len() + bytes.len() + after.len(), |ext| unsafe {
+        self.append_uninit(before.len() + bytes.len() + after.len(), |ext| unsafe {
             ptr::copy_nonoverlapping(before.as_ptr(),
                                      ext.as_mut_ptr(),
                                      before.len());
@@ -90,22 +223,158 @@ impl Buffer {
                                      after.len());
         });
     }
+}
 
-    #[inline]
-    pub fn into_vec(self) -> Vec<u8> {
-        self.data
+/// What to emit for NaN and +/-Infinity, which JSON cannot represent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonFinite {
+    /// `null` (the default)
+    Null,
+    /// a quoted string: `"NaN"`, `"Infinity"` or `"-Infinity"`
+    Str,
+    /// a JSON5-style bare literal: `NaN`, `Infinity` or `-Infinity`
+    Literal,
+    /// nothing; the encoder records `JsonError::NonFiniteFloat` instead
+    Error,
+}
+
+/// How strings and field names represent characters beyond ASCII.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonAscii {
+    /// as UTF-8 (the default)
+    Utf8,
+    /// as `\uXXXX` escapes with lowercase hex digits, using surrogate
+    /// pairs beyond the Basic Multilingual Plane
+    EscapeLower,
+    /// like `EscapeLower`, with uppercase hex digits
+    EscapeUpper,
+}
+
+/// Variants may be added in minor releases, and `Io` only exists with the
+/// `std` feature.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum JsonError {
+    /// a NaN or infinite float was encoded under `NonFinite::Error`
+    NonFiniteFloat,
+    /// a map key that cannot be written as a JSON string
+    KeyMustBeAString,
+    /// an error reported by a `serde::Serialize` implementation
+    Custom(String),
+    /// writing to the output stream failed; `JsonEncoder::flush` returns
+    /// the `io::Error` itself
+    #[cfg(feature = "std")]
+    Io(io::ErrorKind),
+    /// writing to a `fmt::Write` failed, or the output was not UTF-8
+    Fmt,
+    /// the output did not fit into the sink
+    SinkFull,
+    /// containers were nested deeper than `Limits::max_depth`
+    DepthLimitExceeded,
+    /// the output grew beyond `Limits::max_output_len`
+    OutputLimitExceeded,
+    /// a string was longer than `Limits::max_str_len`
+    StrTooLong,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JsonError::NonFiniteFloat => f.write_str("NaN or Infinity cannot be encoded as JSON"),
+            JsonError::KeyMustBeAString => f.write_str("key must be a string"),
+            JsonError::Custom(ref msg) => f.write_str(msg),
+            #[cfg(feature = "std")]
+            JsonError::Io(kind) => write!(f, "I/O error: {:?}", kind),
+            JsonError::Fmt => f.write_str("formatter error"),
+            JsonError::SinkFull => f.write_str("output buffer is full"),
+            JsonError::DepthLimitExceeded => f.write_str("maximum nesting depth exceeded"),
+            JsonError::OutputLimitExceeded => f.write_str("maximum output size exceeded"),
+            JsonError::StrTooLong => f.write_str("string exceeds the maximum length"),
+        }
+    }
+}
+
+impl error::Error for JsonError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indent {
+    /// that many spaces per nesting level
+    Spaces(usize),
+    /// that many tabs per nesting level
+    Tabs(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Newline {
+    Lf,
+    CrLf,
+}
+
+/// Layout of pretty-printed output, see `JsonEncoder::set_pretty`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrettyConfig {
+    pub indent: Indent,
+    pub newline: Newline,
+    /// whether `:` is followed by a space
+    pub space_after_colon: bool,
+    /// Containers whose compact form (`[1, 2]`, `{"a": 1}`) fits within
+    /// this many columns (bytes) stay on one line. `None` breaks all
+    /// non-empty containers.
+    pub line_width: Option<usize>,
+}
+
+impl Default for PrettyConfig {
+    /// Two spaces, `\n` and `": "`, like `serde_json::to_vec_pretty`.
+    fn default() -> PrettyConfig {
+        PrettyConfig {
+            indent: Indent::Spaces(2),
+            newline: Newline::Lf,
+            space_after_colon: true,
+            line_width: None,
+        }
     }
 }
 
-pub struct JsonEncoder {
-    buffer: Buffer,
+/// Bounds for encoding untrusted data, see `JsonEncoder::set_limits`.
+/// `None` means unbounded, which is the default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Limits {
+    /// how deeply objects and arrays may nest
+    pub max_depth: Option<usize>,
+    /// the total size of the output in bytes, including anything the sink
+    /// already handed over
+    pub max_output_len: Option<usize>,
+    /// the length of a string value or field name in bytes, before escaping
+    pub max_str_len: Option<usize>,
+}
+
+pub struct JsonEncoder<S = Vec<u8>> {
+    buffer: Buffer<S>,
+    non_finite: NonFinite,
+    non_ascii: NonAscii,
+    error: Option<JsonError>,
+    pretty: Option<PrettyConfig>,
+    limits: Limits,
+    depth: usize,
+    // start positions of the open containers, with the start of the line
+    // each one begins on, kept only with a line_width
+    open_positions: Vec<(usize, usize)>,
+    // position behind the last newline written in pretty mode
+    line_start: usize,
+    // number of open `try_` calls; output is not drained while they might
+    // roll it back
+    rollback_points: usize,
+    // depth of the outermost open container with a snapshot taken in it;
+    // output is not drained before it closes, for the same reason. A `Cell`
+    // so that taking a snapshot only needs `&self`.
+    snapshot_depth: Cell<Option<usize>>,
 }
 
 const LUT: [u8; 256] = [
-     0 /*   0 */,  0 /*   1 */,  0 /*   2 */,  0 /*   3 */,  0 /*   4 */,  0 /*   5 */,  0 /*   6 */,  0 /*   7 */,
-    98 /*   8 */, 116 /*   9 */, 110 /*  10 */,  0 /*  11 */, 102 /*  12 */, 114 /*  13 */,  0 /*  14 */,  0 /*  15 */,
-     0 /*  16 */,  0 /*  17 */,  0 /*  18 */,  0 /*  19 */,  0 /*  20 */,  0 /*  21 */,  0 /*  22 */,  0 /*  23 */,
-     0 /*  24 */,  0 /*  25 */,  0 /*  26 */,  0 /*  27 */,  0 /*  28 */,  0 /*  29 */,  0 /*  30 */,  0 /*  31 */,
+    117 /*   0 */, 117 /*   1 */, 117 /*   2 */, 117 /*   3 */, 117 /*   4 */, 117 /*   5 */, 117 /*   6 */, 117 /*   7 */,
+    98 /*   8 */, 116 /*   9 */, 110 /*  10 */, 117 /*  11 */, 102 /*  12 */, 114 /*  13 */, 117 /*  14 */, 117 /*  15 */,
+    117 /*  16 */, 117 /*  17 */, 117 /*  18 */, 117 /*  19 */, 117 /*  20 */, 117 /*  21 */, 117 /*  22 */, 117 /*  23 */,
+    117 /*  24 */, 117 /*  25 */, 117 /*  26 */, 117 /*  27 */, 117 /*  28 */, 117 /*  29 */, 117 /*  30 */, 117 /*  31 */,
      0 /*  32 */,  0 /*  33 */, 34 /*  34 */,  0 /*  35 */,  0 /*  36 */,  0 /*  37 */,  0 /*  38 */,  0 /*  39 */,
      0 /*  40 */,  0 /*  41 */,  0 /*  42 */,  0 /*  43 */,  0 /*  44 */,  0 /*  45 */,  0 /*  46 */,  0 /*  47 */,
      0 /*  48 */,  0 /*  49 */,  0 /*  50 */,  0 /*  51 */,  0 /*  52 */,  0 /*  53 */,  0 /*  54 */,  0 /*  55 */,
@@ -137,28 +406,413 @@ const LUT: [u8; 256] = [
 ];
 
 const LUT_BIN: [u64; 4] = [
-    0b0000000000000000000000000000010000000000000000000011011100000000,
+    0b0000000000000000000000000000010011111111111111111111111111111111,
     0b0000000000000000000000000000000000010000000000000000000000000000,
     0b0000000000000000000000000000000000000000000000000000000000000000,
     0b0000000000000000000000000000000000000000000000000000000000000000
 ];
 
-const LUT_HASH: u64 = LUT_BIN[0] | LUT_BIN[1] | LUT_BIN[2] | LUT_BIN[3];
+const HEX_LOWER: &'static [u8] = b"0123456789abcdef";
+const HEX_UPPER: &'static [u8] = b"0123456789ABCDEF";
+
+// "00" to "99", used to emit two decimal digits at a time.
+const DEC_DIGITS_LUT: &'static [u8] =
+    b"0001020304050607080910111213141516171819\
+      2021222324252627282930313233343536373839\
+      4041424344454647484950515253545556575859\
+      6061626364656667686970717273747576777879\
+      8081828384858687888990919293949596979899";
+
+/// Returns the digits a number encoder wrote into `buf[start..]`.
+///
+/// Safety: every byte from `start` on must have been written.
+#[inline(always)]
+unsafe fn written_digits(buf: &[MaybeUninit<u8>], start: usize) -> &[u8] {
+    slice::from_raw_parts(buf.as_ptr().offset(start as isize) as *const u8, buf.len() - start)
+}
+
+macro_rules! encode_unsigned {
+    ($(#[$attr:meta])* fn $name:ident($t:ty), $max_digits:expr) => {
+        $(#[$attr])*
+        #[inline]
+        pub fn $name(&mut self, value: $t) {
+            const MAX_DIGITS: usize = $max_digits;
+
+            let mut digits = [MaybeUninit::<u8>::uninit(); MAX_DIGITS];
+            let mut n = value;
+            let mut start = MAX_DIGITS;
+            while n >= 100 {
+                let d = ((n % 100) as usize) << 1;
+                n = n / 100;
+                start -= 2;
+                unsafe {
+                    *digits.get_unchecked_mut(start) = MaybeUninit::new(*DEC_DIGITS_LUT.get_unchecked(d));
+                    *digits.get_unchecked_mut(start + 1) = MaybeUninit::new(*DEC_DIGITS_LUT.get_unchecked(d + 1));
+                }
+            }
+            if n >= 10 {
+                let d = (n as usize) << 1;
+                start -= 2;
+                unsafe {
+                    *digits.get_unchecked_mut(start) = MaybeUninit::new(*DEC_DIGITS_LUT.get_unchecked(d));
+                    *digits.get_unchecked_mut(start + 1) = MaybeUninit::new(*DEC_DIGITS_LUT.get_unchecked(d + 1));
+                }
+            } else {
+                start -= 1;
+                unsafe { *digits.get_unchecked_mut(start) = MaybeUninit::new(b'0' + n as u8); }
+            }
+
+            self.encode_raw(unsafe { written_digits(&digits, start) });
+        }
+    }
+}
+
+macro_rules! encode_signed {
+    ($(#[$attr:meta])* fn $name:ident($t:ty) => $unsigned:ident($ut:ty)) => {
+        $(#[$attr])*
+        #[inline]
+        pub fn $name(&mut self, value: $t) {
+            if likely(value >= 0) {
+                self.$unsigned(value as $ut);
+            } else {
+                self.buffer.push(b'-');
+                // computed in the unsigned domain so that MIN does not overflow
+                self.$unsigned((value as $ut).wrapping_neg());
+            }
+        }
+    }
+}
+
+/// Writes the escape sequence of `byte` to `dst`, where `escaped2` is its
+/// `LUT` entry. Returns the number of bytes written (2 or 6).
+#[inline(always)]
+unsafe fn write_escaped(dst: *mut u8, byte: u8, escaped2: u8) -> usize {
+    ptr::write(dst, b'\\');
+    ptr::write(dst.offset(1), escaped2);
+    if escaped2 != b'u' {
+        return 2;
+    }
+    ptr::write(dst.offset(2), b'0');
+    ptr::write(dst.offset(3), b'0');
+    ptr::write(dst.offset(4), *HEX_LOWER.get_unchecked((byte >> 4) as usize));
+    ptr::write(dst.offset(5), *HEX_LOWER.get_unchecked((byte & 15) as usize));
+    6
+}
+
+/// Writes `bytes` escaped to `dst`, which must have room for `6 * bytes.len()`
+/// bytes. Returns the number of bytes written.
+#[inline(always)]
+unsafe fn escape_into(dst: *mut u8, bytes: &[u8]) -> usize {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if bytes.len() >= simd::MIN_LEN {
+            return simd::escape_into(dst, bytes);
+        }
+    }
+    escape_into_swar(dst, bytes)
+}
+
+/// Like `escape_into`, checking eight bytes at a time in a `u64` and
+/// finishing with the byte loop. Each word is stored whole, and the output
+/// position advanced past its clean bytes only, so that an escape sequence
+/// overwrites the rest.
+#[inline(always)]
+unsafe fn escape_into_swar(dst: *mut u8, bytes: &[u8]) -> usize {
+    let src = bytes.as_ptr();
+    let mut i = 0;
+    let mut count = 0;
+    while i + 8 <= bytes.len() {
+        let word = ptr::read_unaligned(src.offset(i as isize) as *const u64);
+        ptr::write_unaligned(dst.offset(count as isize) as *mut u64, word);
+
+        let mask = special_bytes(u64::from_le(word));
+        if likely(mask == 0) {
+            i += 8;
+            count += 8;
+            continue;
+        }
+
+        let clean = (mask.trailing_zeros() / 8) as usize;
+        i += clean;
+        count += clean;
+        count += escape_byte(dst.offset(count as isize), bytes, i);
+        i += 1;
+    }
+    count + escape_into_scalar(dst.offset(count as isize), &bytes[i..])
+}
+
+/// Sets the high bit of the lowest byte of `word` that needs escaping.
+/// Higher bytes may be marked falsely after a borrow, but only if a lower
+/// one is marked, too.
+#[inline(always)]
+fn special_bytes(word: u64) -> u64 {
+    const ONES: u64 = 0x0101_0101_0101_0101;
+    const HIGH: u64 = 0x8080_8080_8080_8080;
+
+    #[inline(always)]
+    fn zero_bytes(x: u64) -> u64 {
+        x.wrapping_sub(ONES) & !x & HIGH
+    }
+
+    let control = word.wrapping_sub(ONES * 0x20) & !word & HIGH;
+    control | zero_bytes(word ^ (ONES * b'"' as u64)) | zero_bytes(word ^ (ONES * b'\\' as u64))
+}
+
+/// Like `escape_into`, but also writes every character beyond ASCII as
+/// `\uXXXX`, or as a surrogate pair of them, with digits from `hex`.
+/// `bytes` must be UTF-8.
+unsafe fn escape_into_ascii(dst: *mut u8, bytes: &[u8], hex: &'static [u8]) -> usize {
+    let mut count = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = *bytes.get_unchecked(i);
+        if byte < 0x80 {
+            let escaped2 = *LUT.get_unchecked(byte as usize);
+            if escaped2 == 0 {
+                ptr::write(dst.offset(count as isize), byte);
+                count += 1;
+            } else if escaped2 == b'u' {
+                count += write_u_escape(dst.offset(count as isize), byte as u32, hex);
+            } else {
+                count += write_escaped(dst.offset(count as isize), byte, escaped2);
+            }
+            i += 1;
+            continue;
+        }
+
+        let c = match str::from_utf8_unchecked(bytes.get_unchecked(i..)).chars().next() {
+            Some(c) => c,
+            None => break,
+        };
+        let code = c as u32;
+        if code < 0x10000 {
+            count += write_u_escape(dst.offset(count as isize), code, hex);
+        } else {
+            let code = code - 0x10000;
+            count += write_u_escape(dst.offset(count as isize), 0xd800 | (code >> 10), hex);
+            count += write_u_escape(dst.offset(count as isize), 0xdc00 | (code & 0x3ff), hex);
+        }
+        i += c.len_utf8();
+    }
+    count
+}
+
+/// Writes `\uXXXX` for a UTF-16 code unit, returning its length.
+#[inline(always)]
+unsafe fn write_u_escape(dst: *mut u8, unit: u32, hex: &'static [u8]) -> usize {
+    ptr::write(dst, b'\\');
+    ptr::write(dst.offset(1), b'u');
+    ptr::write(dst.offset(2), *hex.get_unchecked(((unit >> 12) & 15) as usize));
+    ptr::write(dst.offset(3), *hex.get_unchecked(((unit >> 8) & 15) as usize));
+    ptr::write(dst.offset(4), *hex.get_unchecked(((unit >> 4) & 15) as usize));
+    ptr::write(dst.offset(5), *hex.get_unchecked((unit & 15) as usize));
+    6
+}
+
+/// Writes the escape sequence for `bytes[i]`, returning its length.
+#[inline(always)]
+unsafe fn escape_byte(dst: *mut u8, bytes: &[u8], i: usize) -> usize {
+    let byte = *bytes.get_unchecked(i);
+    write_escaped(dst, byte, *LUT.get_unchecked(byte as usize))
+}
+
+/// Like `escape_into`, a byte at a time.
+#[inline(always)]
+unsafe fn escape_into_scalar(dst: *mut u8, bytes: &[u8]) -> usize {
+    let mut count: usize = 0;
+    for &byte in bytes.iter() {
+        // With all control characters escapable, a hash over the lower
+        // 6 bits would hit every upper-case letter, so look up the
+        // exact bit instead.
+        let reg = *LUT_BIN.get_unchecked((byte >> 6) as usize);
+
+        if likely((reg >> (byte & 63)) & 1 == 0) {
+            ptr::write(dst.offset(count as isize), byte);
+            count += 1;
+        } else {
+            let escaped2: u8 = *LUT.get_unchecked(byte as usize);
+            count += write_escaped(dst.offset(count as isize), byte, escaped2);
+        }
+    }
+    count
+}
 
 impl JsonEncoder {
     #[inline]
     pub fn new() -> JsonEncoder {
-        JsonEncoder{buffer: Buffer::new()}
+        JsonEncoder::with_sink(Vec::new())
+    }
+
+    #[inline]
+    pub fn with_capacity(capa: usize) -> JsonEncoder {
+        JsonEncoder::with_sink(Vec::with_capacity(capa))
+    }
+
+    #[inline]
+    pub fn into_vec(self) -> Vec<u8> {
+        self.buffer.into_vec()
+    }
+
+    #[inline]
+    pub fn obj_single_str_field(name: &str, s: &str) -> Vec<u8> {
+        let mut js = JsonEncoder::with_capacity(name.len() + s.len() + 2 + 2 + 1 + 2);
+        js.encode_obj(|jso| jso.encode_field_str(name, s));
+        js.into_vec()
+    }
+}
+
+impl Default for JsonEncoder {
+    fn default() -> JsonEncoder {
+        JsonEncoder::new()
+    }
+}
+
+impl<S: Sink> JsonEncoder<S> {
+    /// Creates an encoder writing into `sink`, see the `sink` module.
+    #[inline]
+    pub fn with_sink(sink: S) -> JsonEncoder<S> {
+        JsonEncoder {
+            buffer: Buffer::with_sink(sink),
+            non_finite: NonFinite::Null,
+            non_ascii: NonAscii::Utf8,
+            error: None,
+            pretty: None,
+            limits: Limits::default(),
+            depth: 0,
+            open_positions: Vec::new(),
+            line_start: 0,
+            rollback_points: 0,
+            snapshot_depth: Cell::new(None),
+        }
     }
 
     #[inline]
     pub fn clear(&mut self) {
         self.buffer.clear();
+        self.buffer.max_len = self.limits.max_output_len.unwrap_or(usize::MAX);
+        self.error = None;
+        self.depth = 0;
+        self.open_positions.clear();
+        self.line_start = 0;
+        self.rollback_points = 0;
+        self.snapshot_depth.set(None);
     }
 
     #[inline]
-    pub fn with_capacity(capa: usize) -> JsonEncoder {
-        JsonEncoder{buffer: Buffer::with_capacity(capa)}
+    fn drain(&mut self) {
+        if self.rollback_points > 0 || self.snapshot_depth.get().is_some() {
+            return;
+        }
+        if let Err(err) = self.buffer.drain() {
+            self.set_error(err);
+        }
+    }
+
+    /// Switches between compact (`None`, the default) and pretty-printed
+    /// output. Indentation follows the nesting depth of the containers.
+    /// Only change this between top-level values.
+    #[inline]
+    pub fn set_pretty(&mut self, pretty: Option<PrettyConfig>) {
+        self.pretty = pretty;
+    }
+
+    #[inline]
+    pub fn pretty(&self) -> Option<PrettyConfig> {
+        self.pretty
+    }
+
+    /// Sets what `encode_f64` and `encode_f32` emit for NaN and infinities.
+    #[inline]
+    pub fn set_non_finite(&mut self, policy: NonFinite) {
+        self.non_finite = policy;
+    }
+
+    #[inline]
+    pub fn non_finite(&self) -> NonFinite {
+        self.non_finite
+    }
+
+    /// Sets how strings and field names write non-ASCII characters,
+    /// including `JsonKey`s and pre-escaped ones. Only `encode_raw` writes
+    /// its input as it is.
+    #[inline]
+    pub fn set_non_ascii(&mut self, policy: NonAscii) {
+        self.non_ascii = policy;
+    }
+
+    #[inline]
+    pub fn non_ascii(&self) -> NonAscii {
+        self.non_ascii
+    }
+
+    /// Sets bounds on nesting, output size and string length. Exceeding
+    /// one records the matching error, after which no more output is
+    /// written, unless a `try_` closure rolls it back.
+    #[inline]
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        if self.error.is_none() {
+            self.buffer.max_len = limits.max_output_len.unwrap_or(usize::MAX);
+        }
+    }
+
+    #[inline]
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Returns the first error recorded while encoding, if any. The output
+    /// is not valid JSON in that case.
+    #[inline]
+    pub fn error(&self) -> Option<&JsonError> {
+        match self.error {
+            Some(ref err) => Some(err),
+            None if self.buffer.over_limit => Some(&JsonError::OutputLimitExceeded),
+            None if self.buffer.full => Some(&JsonError::SinkFull),
+            None => None,
+        }
+    }
+
+    /// Lets the sink hand over the rest of the output and returns it, or
+    /// the first error recorded while encoding.
+    #[inline]
+    pub fn finish(mut self) -> Result<S, JsonError> {
+        let result = self.buffer.sink.finish();
+        if let Some(err) = self.error() {
+            return Err(err.clone());
+        }
+        result.map(|_| self.buffer.into_sink())
+    }
+
+    #[inline]
+    pub fn into_sink(self) -> S {
+        self.buffer.into_sink()
+    }
+
+    #[cold]
+    fn set_error(&mut self, err: JsonError) {
+        if self.error.is_none() {
+            self.error = Some(err);
+        }
+    }
+
+    #[cold]
+    fn limit_exceeded(&mut self, err: JsonError) {
+        self.set_error(err);
+        // refuse all further output
+        self.buffer.max_len = 0;
+    }
+
+    /// Records `StrTooLong` if a string of `len` bytes is over the limit.
+    #[inline(always)]
+    fn check_str_len(&mut self, len: usize) -> bool {
+        match self.limits.max_str_len {
+            Some(max) if len > max => {
+                self.limit_exceeded(JsonError::StrTooLong);
+                false
+            }
+            _ => true,
+        }
     }
 
     #[inline]
@@ -172,26 +826,28 @@ impl JsonEncoder {
     }
 
     #[inline]
-    pub fn into_vec(self) -> Vec<u8> {
-        self.buffer.into_vec()
-    }
-
-    #[inline]
-    pub fn with_buffer<F>(&mut self, mut f: F) where F: FnMut(&mut Buffer) {
+    pub fn with_buffer<F>(&mut self, mut f: F) where F: FnMut(&mut Buffer<S>) {
         f(&mut self.buffer);
     }
 
     #[inline]
     pub fn encode_str_noescape(&mut self, raw_str: &str) {
+        if self.non_ascii != NonAscii::Utf8 && !raw_str.is_ascii() {
+            return self.encode_str(raw_str);
+        }
+        if !self.check_str_len(raw_str.len()) {
+            return;
+        }
         self.buffer.push_all_around(b'"', raw_str.as_bytes());
     }
 
     #[inline]
     fn escape_bytes(&mut self, bytes: &[u8]) {
         let mut start = 0;
+        let mut uescape: [u8; 6] = *b"\\u0000";
 
         for (i, byte) in bytes.iter().enumerate() {
-            let escaped = match *byte {
+            let escaped: &[u8] = match *byte {
                 b'"' => b"\\\"",
                 b'\\' => b"\\\\",
                 b'\x08' => b"\\b",
@@ -199,6 +855,11 @@ impl JsonEncoder {
                 b'\n' => b"\\n",
                 b'\r' => b"\\r",
                 b'\t' => b"\\t",
+                b if b < 0x20 => {
+                    uescape[4] = HEX_LOWER[(b >> 4) as usize];
+                    uescape[5] = HEX_LOWER[(b & 15) as usize];
+                    &uescape[..]
+                }
                 _ => {
                     continue;
                 }
@@ -221,26 +882,28 @@ impl JsonEncoder {
     // encodes as decimal string
     #[inline]
     pub fn encode_decimal_str(&mut self, value: u64) {
-        use std::mem;
         const CHARS: &'static [u8] = b"0123456789";
         const MAX_DIGITS: usize = 20;
 
-        let mut digits: [u8; MAX_DIGITS] = unsafe{ mem::uninitialized() };
+        let mut digits = [MaybeUninit::<u8>::uninit(); MAX_DIGITS];
         let mut n = value;
         let mut start = MAX_DIGITS;
         for digit in digits.iter_mut().rev() {
             start -= 1;
-            *digit = unsafe { *CHARS.get_unchecked((n % 10) as usize) };
+            *digit = MaybeUninit::new(unsafe { *CHARS.get_unchecked((n % 10) as usize) });
             n = n / 10;
             if n == 0 {
                 break;
             }
         }
-        self.encode_raw_around(b'"', &digits[start..]);
+        self.encode_raw_around(b'"', unsafe { written_digits(&digits, start) });
     }
 
     #[inline]
     pub fn encode_str2(&mut self, s: &str) {
+        if !self.check_str_len(s.len()) {
+            return;
+        }
         self.buffer.push(b'"');
         self.escape_bytes(s.as_bytes());
         self.buffer.push(b'"');
@@ -248,8 +911,11 @@ impl JsonEncoder {
 
     #[inline]
     pub fn encode_str3(&mut self, s: &str) {
+        if !self.check_str_len(s.len()) {
+            return;
+        }
         let bytes = s.as_bytes();
-        append_bytes_uninit_flex(&mut self.buffer.data, 2*bytes.len() + 2, |ext| {
+        let done = self.buffer.append_uninit_flex(6*bytes.len() + 2, |ext| {
             let dst = ext.as_mut_ptr();
             let mut count: isize = 0;
 
@@ -266,6 +932,7 @@ impl JsonEncoder {
                     b'\n' => b'n',
                     b'\r' => b'r',
                     b'\t' => b't',
+                    b if b < 0x20 => b'u',
                     _ => {
                         continue;
                     }
@@ -277,10 +944,7 @@ impl JsonEncoder {
                     count += wr_count as isize;
                 }
 
-                unsafe { ptr::write(dst.offset(count), b'\\'); }
-                count += 1;
-                unsafe { ptr::write(dst.offset(count), escaped2); }
-                count += 1;
+                count += unsafe { write_escaped(dst.offset(count), byte, escaped2) } as isize;
 
                 start = i + 1;
             }
@@ -294,12 +958,18 @@ impl JsonEncoder {
             count += 1;
             count as usize
         });
+        if !done {
+            self.encode_str2(s);
+        }
     }
 
     #[inline]
     pub fn encode_str_(&mut self, s: &str) {
+        if !self.check_str_len(s.len()) {
+            return;
+        }
         let bytes = s.as_bytes();
-        append_bytes_uninit_flex(&mut self.buffer.data, 2*bytes.len() + 2, |ext| {
+        let done = self.buffer.append_uninit_flex(6*bytes.len() + 2, |ext| {
             let dst = ext.as_mut_ptr();
 
             unsafe { ptr::write(dst, b'"'); }
@@ -308,10 +978,7 @@ impl JsonEncoder {
             for &byte in bytes.iter() {
                 let escaped2: u8 = unsafe { *LUT.get_unchecked(byte as usize) };
                 if escaped2 != 0 {
-                    unsafe { ptr::write(dst.offset(count as isize), b'\\'); }
-                    count += 1;
-                    unsafe { ptr::write(dst.offset(count as isize), escaped2); }
-                    count += 1;
+                    count += unsafe { write_escaped(dst.offset(count as isize), byte, escaped2) };
                 } else {
                     unsafe { ptr::write(dst.offset(count as isize), byte); }
                     count += 1;
@@ -322,443 +989,1632 @@ impl JsonEncoder {
             count += 1;
             count
         });
+        if !done {
+            self.encode_str2(s);
+        }
     }
  
     #[inline]
     pub fn encode_str(&mut self, s: &str) {
-        let bytes = s.as_bytes();
-        append_bytes_uninit_flex(&mut self.buffer.data, 2*bytes.len() + 2, |ext| {
+        if !self.check_str_len(s.len()) {
+            return;
+        }
+        self.encode_escaped_around(b"\"", s.as_bytes(), b"\"");
+    }
+
+    /// Writes `before`, then `bytes` escaped, then `after`, into a single
+    /// reservation. `bytes` must be UTF-8.
+    #[inline(always)]
+    fn encode_escaped_around(&mut self, before: &[u8], bytes: &[u8], after: &[u8]) {
+        if self.non_ascii != NonAscii::Utf8 {
+            self.encode_escaped_around_ascii(before, bytes, after);
+            return;
+        }
+        let done = self.buffer.append_uninit_flex(before.len() + 6*bytes.len() + after.len(), |ext| unsafe {
             let dst = ext.as_mut_ptr();
+            ptr::copy_nonoverlapping(before.as_ptr(), dst, before.len());
+            let mut count = before.len();
+            count += escape_into(dst.offset(count as isize), bytes);
+            ptr::copy_nonoverlapping(after.as_ptr(), dst.offset(count as isize), after.len());
+            count + after.len()
+        });
+        if !done {
+            self.buffer.push_all(before);
+            self.escape_bytes(bytes);
+            self.buffer.push_all(after);
+        }
+    }
 
-            unsafe { ptr::write(dst, b'"'); }
-            let mut count: usize = 1;
+    /// Like `encode_str`, but takes the string already quoted and escaped,
+    /// as in `"value"`.
+    #[inline]
+    pub fn encode_str_preescaped(&mut self, quoted: &[u8]) {
+        self.push_preescaped(quoted);
+    }
 
-            for &byte in bytes.iter() {
-                //let lut_idx = (byte >> 6) as usize; // highest 2-bits
-                //let reg = unsafe { *LUT_BIN.get_unchecked(lut_idx) };
-                //let bit = ((LUT_HASH/*reg*/ >> (byte & 63)) as u8) & 1;
+    /// Writes already escaped JSON, escaping non-ASCII characters unless
+    /// they may be written as UTF-8.
+    #[inline(always)]
+    fn push_preescaped(&mut self, bytes: &[u8]) {
+        if self.non_ascii != NonAscii::Utf8 && !bytes.is_ascii() {
+            self.push_preescaped_ascii(bytes);
+        } else {
+            self.buffer.push_all(bytes);
+        }
+    }
 
-                if unsafe { expect_u64((LUT_HASH >> (byte & 63)) << 63, 0) } == 0 {
-                    // likely
-                    unsafe { ptr::write(dst.offset(count as isize), byte); }
-                    count += 1;
-                } else {
-                    let escaped2: u8 = unsafe { *LUT.get_unchecked(byte as usize) };
-                    if unsafe { expect_u8(escaped2, 0) } == 0 {
-                        unsafe { ptr::write(dst.offset(count as isize), byte); }
-                        count += 1;
-                    } else {
-                        unsafe { ptr::write(dst.offset(count as isize), b'\\'); }
-                        count += 1;
-                        unsafe { ptr::write(dst.offset(count as isize), escaped2); }
-                        count += 1;
-                    }
-                }
+    #[inline(never)]
+    fn push_preescaped_ascii(&mut self, bytes: &[u8]) {
+        let s = match str::from_utf8(bytes) {
+            Ok(s) => s,
+            // not valid JSON anyway
+            Err(_) => return self.buffer.push_all(bytes),
+        };
+        let hex = self.hex_digits();
+        let mut start = 0;
+        for (i, c) in s.char_indices() {
+            if c.is_ascii() {
+                continue;
             }
-
-            unsafe { ptr::write(dst.offset(count as isize), b'"'); }
-            count += 1;
-            count
-        });
+            self.buffer.push_all(&bytes[start..i]);
+            let mut utf8 = [0u8; 4];
+            let mut escaped = [0u8; 12];
+            let len = unsafe { escape_into_ascii(escaped.as_mut_ptr(), c.encode_utf8(&mut utf8).as_bytes(), hex) };
+            self.buffer.push_all(&escaped[..len]);
+            start = i + c.len_utf8();
+        }
+        self.buffer.push_all(&bytes[start..]);
     }
 
-
+    /// The digits of `\uXXXX` escapes for non-ASCII characters.
     #[inline]
-    pub fn encode_i32(&mut self, value: i32) {
-        if value >= 0 {
-            self.encode_u32(value as u32);
-        } else {
-            self.buffer.push(b'-');
-            self.encode_u32((-value) as u32);
+    fn hex_digits(&self) -> &'static [u8] {
+        match self.non_ascii {
+            NonAscii::EscapeUpper => HEX_UPPER,
+            _ => HEX_LOWER,
+        }
+    }
+
+    /// Like `encode_escaped_around`, escaping non-ASCII characters, too.
+    #[inline(never)]
+    fn encode_escaped_around_ascii(&mut self, before: &[u8], bytes: &[u8], after: &[u8]) {
+        let hex = self.hex_digits();
+        let done = self.buffer.append_uninit_flex(before.len() + 6*bytes.len() + after.len(), |ext| unsafe {
+            let dst = ext.as_mut_ptr();
+            ptr::copy_nonoverlapping(before.as_ptr(), dst, before.len());
+            let mut count = before.len();
+            count += escape_into_ascii(dst.offset(count as isize), bytes, hex);
+            ptr::copy_nonoverlapping(after.as_ptr(), dst.offset(count as isize), after.len());
+            count + after.len()
+        });
+        if !done {
+            // a character at a time, each escaped to at most 12 bytes
+            let s = unsafe { str::from_utf8_unchecked(bytes) };
+            let mut escaped = [0u8; 12];
+            self.buffer.push_all(before);
+            for c in s.chars() {
+                let mut utf8 = [0u8; 4];
+                let len = unsafe { escape_into_ascii(escaped.as_mut_ptr(), c.encode_utf8(&mut utf8).as_bytes(), hex) };
+                self.buffer.push_all(&escaped[..len]);
+            }
+            self.buffer.push_all(after);
         }
     }
 
+
+    encode_signed!(
+        /// encodes a 32-bit signed integer
+        fn encode_i32(i32) => encode_u32(u32));
+
     /// encodes a 32-bit unsigned integer
     #[inline]
     pub fn encode_u32(&mut self, value: u32) {
-        use std::mem;
         const CHARS: &'static [u8] = b"0123456789";
         const MAX_DIGITS: usize = 10;
 
-        let mut digits: [u8; MAX_DIGITS] = unsafe{ mem::uninitialized() };
+        let mut digits = [MaybeUninit::<u8>::uninit(); MAX_DIGITS];
         let mut n = value;
         let mut start = MAX_DIGITS;
         for digit in digits.iter_mut().rev() {
             start -= 1;
-            *digit = unsafe { *CHARS.get_unchecked((n % 10) as usize) };
+            *digit = MaybeUninit::new(unsafe { *CHARS.get_unchecked((n % 10) as usize) });
             n = n / 10;
             if n == 0 {
                 break;
             }
         }
 
-        self.encode_raw(&digits[start..]);
+        self.encode_raw(unsafe { written_digits(&digits, start) });
     }
 
-    /// encodes a 32-bit unsigned integer as hexadecimal
+    encode_unsigned!(
+        /// encodes a 64-bit unsigned integer
+        fn encode_u64(u64), 20);
+
+    encode_unsigned!(
+        /// encodes a 128-bit unsigned integer
+        fn encode_u128(u128), 39);
+
+    encode_signed!(
+        /// encodes a 64-bit signed integer
+        fn encode_i64(i64) => encode_u64(u64));
+
+    encode_signed!(
+        /// encodes a 128-bit signed integer
+        fn encode_i128(i128) => encode_u128(u128));
+
     #[inline]
-    pub fn encode_hex_u32(&mut self, value: u32) {
-        use std::mem;
-        const CHARS: &'static [u8] = b"0123456789ABCDEF";
-        const MAX_DIGITS: usize = 8;
+    pub fn encode_usize(&mut self, value: usize) {
+        self.encode_u64(value as u64);
+    }
 
-        let mut digits: [u8; MAX_DIGITS] = unsafe{ mem::uninitialized() };
-        let mut n = value;
+    #[inline]
+    pub fn encode_isize(&mut self, value: isize) {
+        self.encode_i64(value as i64);
+    }
+
+    /// encodes a 32-bit unsigned integer as hexadecimal
+    #[inline]
+    pub fn encode_hex_u32(&mut self, value: u32) {
+        const MAX_DIGITS: usize = 8;
+
+        let mut digits = [MaybeUninit::<u8>::uninit(); MAX_DIGITS];
+        let mut n = value;
         let mut start = MAX_DIGITS;
         for digit in digits.iter_mut().rev() {
             start -= 1;
-            *digit = unsafe { *CHARS.get_unchecked((n & 15) as usize) };
+            *digit = MaybeUninit::new(unsafe { *HEX_UPPER.get_unchecked((n & 15) as usize) });
             n = n / 16;
             if n == 0 {
                 break;
             }
         }
 
-        self.encode_raw(&digits[start..]);
+        self.encode_raw(unsafe { written_digits(&digits, start) });
     }
 
     #[inline]
     pub fn encode_hex_u32_fast(&mut self, n: u32) {
-        const CHARS: &'static [u8] = b"0123456789ABCDEF";
         const MAX_DIGITS: usize = 8;
 
         let digits: [u8; MAX_DIGITS] = [
-           unsafe { *CHARS.get_unchecked( ((n >> 28) & 15) as usize ) },
-           unsafe { *CHARS.get_unchecked( ((n >> 24) & 15) as usize ) },
-           unsafe { *CHARS.get_unchecked( ((n >> 20) & 15) as usize ) },
-           unsafe { *CHARS.get_unchecked( ((n >> 16) & 15) as usize ) },
-           unsafe { *CHARS.get_unchecked( ((n >> 12) & 15) as usize ) },
-           unsafe { *CHARS.get_unchecked( ((n >>  8) & 15) as usize ) },
-           unsafe { *CHARS.get_unchecked( ((n >>  4) & 15) as usize ) },
-           unsafe { *CHARS.get_unchecked( ((n >>  0) & 15) as usize ) }
+           unsafe { *HEX_UPPER.get_unchecked( ((n >> 28) & 15) as usize ) },
+           unsafe { *HEX_UPPER.get_unchecked( ((n >> 24) & 15) as usize ) },
+           unsafe { *HEX_UPPER.get_unchecked( ((n >> 20) & 15) as usize ) },
+           unsafe { *HEX_UPPER.get_unchecked( ((n >> 16) & 15) as usize ) },
+           unsafe { *HEX_UPPER.get_unchecked( ((n >> 12) & 15) as usize ) },
+           unsafe { *HEX_UPPER.get_unchecked( ((n >>  8) & 15) as usize ) },
+           unsafe { *HEX_UPPER.get_unchecked( ((n >>  4) & 15) as usize ) },
+           unsafe { *HEX_UPPER.get_unchecked( ((n >>  0) & 15) as usize ) }
         ];
 
         self.encode_raw(&digits[..]);
     }
 
     #[inline]
-    pub fn encode_obj<F, T>(&mut self, mut f: F) -> T where F: FnMut(&mut JsonObjectEncoder) -> T {
-        self.buffer.push(b'{');
-        let t = {
-            f(&mut JsonObjectEncoder {js: self, needs_sep: false})
-        };
-        self.buffer.push(b'}');
-        t
+    pub fn encode_bool(&mut self, value: bool) {
+        if value {
+            self.buffer.push_all(b"true");
+        } else {
+            self.buffer.push_all(b"false");
+        }
     }
 
     #[inline]
-    pub fn encode_array<F, T>(&mut self, mut f: F) -> T where F: FnMut(&mut JsonArrayEncoder) -> T {
-        self.buffer.push(b'[');
-        let t = {
-            f(&mut JsonArrayEncoder {js: self, needs_sep: false})
+    pub fn encode_null(&mut self) {
+        self.buffer.push_all(b"null");
+    }
+
+    #[cold]
+    fn encode_non_finite(&mut self, is_nan: bool, is_negative: bool) {
+        let literal: &'static [u8] = if is_nan {
+            b"NaN"
+        } else if is_negative {
+            b"-Infinity"
+        } else {
+            b"Infinity"
         };
-        self.buffer.push(b']');
-        t
+        match self.non_finite {
+            NonFinite::Null => self.encode_null(),
+            NonFinite::Str => self.buffer.push_all_around(b'"', literal),
+            NonFinite::Literal => self.buffer.push_all(literal),
+            NonFinite::Error => self.set_error(JsonError::NonFiniteFloat),
+        }
     }
 
+    /// encodes a 64-bit float using the shortest decimal representation
+    /// that round-trips. NaN and infinities are encoded according to
+    /// `non_finite()`.
     #[inline]
-    pub fn encode_array_nobrackets<F, T>(&mut self, mut f: F) -> T where F: FnMut(&mut JsonArrayEncoder) -> T {
-        f(&mut JsonArrayEncoder {js: self, needs_sep: false})
+    pub fn encode_f64(&mut self, value: f64) {
+        if !value.is_finite() {
+            self.encode_non_finite(value.is_nan(), value.is_sign_negative());
+            return;
+        }
+        // ryu writes at most 24 bytes for an f64
+        let done = self.buffer.append_uninit_flex(24, |ext| unsafe {
+            ryu::raw::format64(value, ext.as_mut_ptr())
+        });
+        if !done {
+            self.encode_raw(ryu::Buffer::new().format_finite(value).as_bytes());
+        }
     }
 
+    /// encodes a 32-bit float using the shortest decimal representation
+    /// that round-trips. NaN and infinities are encoded according to
+    /// `non_finite()`.
     #[inline]
-    pub fn obj_single_str_field(name: &str, s: &str) -> Vec<u8> {
-        let mut js = JsonEncoder::with_capacity(name.len() + s.len() + 2 + 2 + 1 + 2);
-        js.encode_obj(|jso| jso.encode_field_str(name, s));
-        js.into_vec()
+    pub fn encode_f32(&mut self, value: f32) {
+        if !value.is_finite() {
+            self.encode_non_finite(value.is_nan(), value.is_sign_negative());
+            return;
+        }
+        // ryu writes at most 16 bytes for an f32
+        let done = self.buffer.append_uninit_flex(16, |ext| unsafe {
+            ryu::raw::format32(value, ext.as_mut_ptr())
+        });
+        if !done {
+            self.encode_raw(ryu::Buffer::new().format_finite(value).as_bytes());
+        }
     }
 
+    /// Like `encode_f64`, but returns the error right away instead of
+    /// recording it when the value is rejected by `NonFinite::Error`.
     #[inline]
-    pub fn obj<'a>(&'a mut self) -> JsonObj<'a> {
-        JsonObj::open(self)
+    pub fn try_encode_f64(&mut self, value: f64) -> Result<(), JsonError> {
+        if !value.is_finite() && self.non_finite == NonFinite::Error {
+            return Err(JsonError::NonFiniteFloat);
+        }
+        self.encode_f64(value);
+        Ok(())
     }
-}
 
-pub struct JsonObj<'a> {
-    js: &'a mut JsonEncoder,
-    elm_count: usize,
-}
-
-pub struct JsonVal<'a> {
-    js: &'a mut JsonEncoder,
-}
-
-pub struct JsonVec<'a> {
-    js: &'a mut JsonEncoder,
-    elm_count: usize,
-}
-
-pub trait JsonEncodable {
-    fn encode(&self, &mut JsonEncoder);
-}
-
-impl<'a> JsonEncodable for &'a str {
     #[inline]
-    fn encode(&self, js: &mut JsonEncoder) {
-        js.encode_str(self);
+    pub fn try_encode_f32(&mut self, value: f32) -> Result<(), JsonError> {
+        if !value.is_finite() && self.non_finite == NonFinite::Error {
+            return Err(JsonError::NonFiniteFloat);
+        }
+        self.encode_f32(value);
+        Ok(())
     }
-}
 
-impl JsonEncodable for i32 {
+    // The structural parts of the output (brackets, separators and keys)
+    // are all written by the following methods, which add newlines and
+    // indentation in pretty mode.
+
     #[inline]
-    fn encode(&self, js: &mut JsonEncoder) {
-        js.encode_i32(*self);
+    fn begin_container(&mut self, open: u8) {
+        if let Some(PrettyConfig {line_width: Some(_), ..}) = self.pretty {
+            self.open_positions.push((self.buffer.get_current_position(), self.line_start));
+        }
+        if let Some(max) = self.limits.max_depth {
+            if self.depth >= max {
+                self.limit_exceeded(JsonError::DepthLimitExceeded);
+            }
+        }
+        self.buffer.push(open);
+        self.depth += 1;
     }
-}
 
-impl<'a> JsonVal<'a> {
     #[inline]
-    pub fn value<T:JsonEncodable>(self, val: T) {
-        val.encode(self.js);
+    fn end_container(&mut self, close: u8, non_empty: bool) {
+        self.depth -= 1;
+        self.release_snapshots();
+        if non_empty && self.pretty.is_some() {
+            self.write_newline_indent();
+        }
+        self.buffer.push(close);
+        if let Some(PrettyConfig {line_width: Some(width), space_after_colon, ..}) = self.pretty {
+            if let Some((start, line_start)) = self.open_positions.pop() {
+                if non_empty {
+                    self.compact_container(start, line_start, width, space_after_colon);
+                }
+            }
+        }
+        self.drain();
+    }
+
+    /// Rewrites the just closed container starting at `start`, on the line
+    /// starting at `line_start`, onto a single line, if it fits within
+    /// `width` columns. Nested containers were already compacted when they
+    /// closed.
+    #[inline(never)]
+    fn compact_container(&mut self, start: usize, line_start: usize, width: usize, space_after_colon: bool) {
+        let column = start - line_start;
+        let start = match start.checked_sub(self.buffer.flushed) {
+            Some(start) => start,
+            None => return,
+        };
+        let data = self.buffer.sink.as_mut_slice();
+        if column >= width {
+            return;
+        }
+        let budget = width - column;
+
+        // First pass: measure, bailing out as soon as it does not fit.
+        let end = data.len();
+        let mut len = 0;
+        let mut in_str = false;
+        let mut escaped = false;
+        for &b in &data[start..] {
+            if in_str {
+                if escaped {
+                    escaped = false;
+                } else if b == b'\\' {
+                    escaped = true;
+                } else if b == b'"' {
+                    in_str = false;
+                }
+                len += 1;
+            } else {
+                len += match b {
+                    b'\n' | b'\r' | b' ' | b'\t' => 0,
+                    b',' => 2,
+                    b':' if space_after_colon => 2,
+                    b'"' => { in_str = true; 1 }
+                    _ => 1,
+                };
+            }
+            if len > budget {
+                return;
+            }
+        }
+
+        // Second pass: write the compact form into the room behind the
+        // output, then move it into place. Raw JSON without spaces after
+        // commas would grow, and is left as it is.
+        if start + len > end || !self.buffer.sink.reserve(len) {
+            return;
+        }
+        // Everything goes through one pointer, so that reading the output
+        // and writing the uninitialized room behind it do not invalidate
+        // each other. The length of the sink only ever shrinks.
+        unsafe {
+            let base = self.buffer.sink.spare_ptr().offset(-(end as isize));
+            let mut w = end;
+            let mut in_str = false;
+            let mut escaped = false;
+            for i in start..end {
+                let b = *base.offset(i as isize);
+                if in_str {
+                    if escaped {
+                        escaped = false;
+                    } else if b == b'\\' {
+                        escaped = true;
+                    } else if b == b'"' {
+                        in_str = false;
+                    }
+                } else {
+                    match b {
+                        b'\n' | b'\r' | b' ' | b'\t' => continue,
+                        b',' | b':' if b == b',' || space_after_colon => {
+                            ptr::write(base.offset(w as isize), b);
+                            ptr::write(base.offset(w as isize + 1), b' ');
+                            w += 2;
+                            continue;
+                        }
+                        b'"' => in_str = true,
+                        _ => {}
+                    }
+                }
+                ptr::write(base.offset(w as isize), b);
+                w += 1;
+            }
+            debug_assert_eq!(end + len, w);
+            ptr::copy_nonoverlapping(base.offset(end as isize), base.offset(start as isize), len);
+        }
+        self.buffer.sink.truncate(start + len);
+        self.line_start = line_start;
     }
 
+    /// Writes what precedes an array element or object field.
     #[inline]
-    pub fn obj(self) -> JsonObj<'a> {
-        JsonObj::open(self.js)
+    fn begin_element(&mut self, first: bool) {
+        self.drain();
+        if !first {
+            self.buffer.push(b',');
+        }
+        if self.pretty.is_some() {
+            self.write_newline_indent();
+        }
     }
 
+    /// Writes what precedes the value of an object field, escaping `name`.
     #[inline]
-    pub fn vec(self) -> JsonVec<'a> {
-        JsonVec::open(self.js)
+    fn begin_field(&mut self, first: bool, name: &[u8]) {
+        if !self.check_str_len(name.len()) {
+            return;
+        }
+        if self.pretty.is_none() {
+            self.drain();
+            self.encode_escaped_around(if first { b"\"" } else { b",\"" }, name, b"\":");
+        } else {
+            self.begin_element(first);
+            self.encode_escaped_around(b"\"", name, b"\"");
+            self.write_colon();
+        }
     }
-}
 
-impl<'a> JsonObj<'a> {
+    /// Like `begin_field`, for names that need no escaping.
     #[inline]
-    fn open<'b>(js: &'b mut JsonEncoder) -> JsonObj<'b> {
-        js.buffer.push(b'{');
-        JsonObj {js: js, elm_count: 0}
+    fn begin_field_raw(&mut self, first: bool, name: &[u8]) {
+        if self.non_ascii != NonAscii::Utf8 && !name.is_ascii() {
+            return self.begin_field(first, name);
+        }
+        if !self.check_str_len(name.len()) {
+            return;
+        }
+        if self.pretty.is_none() {
+            self.drain();
+            if first {
+                self.buffer.push_all_around2(b"\"", name, b"\":");
+            } else {
+                self.buffer.push_all_around2(b",\"", name, b"\":");
+            }
+        } else {
+            self.begin_element(first);
+            self.buffer.push_all_around2(b"\"", name, b"\"");
+            self.write_colon();
+        }
     }
 
+    /// Like `begin_field`, for a key escaped beforehand.
     #[inline]
-    pub fn field<'b>(&'b mut self, name: &str) -> JsonVal<'b> {
-        if self.elm_count > 0 {
-            self.js.buffer.push_all_around2(b",\"", name.as_bytes(), b"\":");
+    fn begin_field_key(&mut self, first: bool, key: JsonKey) {
+        if !self.check_str_len(key.name_len) {
+            return;
+        }
+        if self.pretty.is_none() {
+            self.drain();
+            self.push_preescaped(if first { key.without_sep() } else { key.with_sep() });
         } else {
-            self.js.buffer.push_all_around2(b"\"", name.as_bytes(), b"\":");
+            self.begin_element(first);
+            let without_sep = key.without_sep();
+            self.push_preescaped(&without_sep[..without_sep.len() - 1]);
+            self.write_colon();
         }
-        self.elm_count += 1;
-        JsonVal {js: self.js} 
     }
 
     #[inline]
-    pub fn end(self) {
-        self.js.buffer.push(b'}');
+    fn write_colon(&mut self) {
+        match self.pretty {
+            Some(ref pretty) if pretty.space_after_colon => self.buffer.push_all(b": "),
+            _ => self.buffer.push(b':'),
+        }
     }
-}
 
+    #[inline(never)]
+    fn write_newline_indent(&mut self) {
+        const SPACES: &'static [u8] = b"                                ";
+        const TABS: &'static [u8] = b"\t\t\t\t\t\t\t\t\t\t\t\t\t\t\t\t";
 
-pub struct JsonVecSnapshot {
-    elm_count: usize,
-    pos: usize,
-}
-
-impl<'a> JsonVec<'a> {
-    #[inline]
-    fn open<'b>(js: &'b mut JsonEncoder) -> JsonVec<'b> {
-        js.buffer.push(b'[');
-        JsonVec {js: js, elm_count: 0}
+        let pretty = match self.pretty {
+            Some(pretty) => pretty,
+            None => return,
+        };
+        self.buffer.push_all(match pretty.newline {
+            Newline::Lf => b"\n",
+            Newline::CrLf => b"\r\n",
+        });
+        self.line_start = self.buffer.get_current_position();
+        let (chars, width) = match pretty.indent {
+            Indent::Spaces(width) => (SPACES, width),
+            Indent::Tabs(width) => (TABS, width),
+        };
+        let mut n = width * self.depth;
+        while n > 0 {
+            let len = if n < chars.len() { n } else { chars.len() };
+            self.buffer.push_all(&chars[..len]);
+            n -= len;
+        }
     }
 
     #[inline]
-    pub fn snapshot(&self) -> JsonVecSnapshot {
-        JsonVecSnapshot {
-            elm_count: self.elm_count,
-            pos: self.js.buffer.get_current_position(),
-        }
+    pub fn encode_obj<F, T>(&mut self, mut f: F) -> T where F: FnMut(&mut JsonObjectEncoder<S>) -> T {
+        self.begin_container(b'{');
+        let (t, non_empty) = {
+            let mut jso = JsonObjectEncoder {js: self, needs_sep: false};
+            let t = f(&mut jso);
+            (t, jso.needs_sep)
+        };
+        self.end_container(b'}', non_empty);
+        t
     }
 
     #[inline]
-    pub fn rollback(&mut self, snapshot: JsonVecSnapshot) {
-        self.elm_count = snapshot.elm_count;
-        self.js.buffer.set_current_position(snapshot.pos);
+    pub fn encode_array<F, T>(&mut self, mut f: F) -> T where F: FnMut(&mut JsonArrayEncoder<S>) -> T {
+        self.begin_container(b'[');
+        let (t, non_empty) = {
+            let mut jsa = JsonArrayEncoder {js: self, needs_sep: false};
+            let t = f(&mut jsa);
+            (t, jsa.needs_sep)
+        };
+        self.end_container(b']', non_empty);
+        t
     }
 
+    /// Like `encode_obj`, for closures that can fail. On error, including
+    /// an exceeded limit, the object and any error recorded meanwhile are
+    /// removed again, and the error is returned. `f` is not called if the
+    /// object itself exceeds the depth limit.
     #[inline]
-    pub fn element<'b>(&'b mut self) -> JsonVal<'b> {
-        if self.elm_count > 0 {
-            self.js.buffer.push(b',');
-        }
-        self.elm_count += 1;
-        JsonVal {js: self.js} 
+    pub fn try_encode_obj<F, T, E>(&mut self, mut f: F) -> Result<T, E>
+        where F: FnMut(&mut JsonObjectEncoder<S>) -> Result<T, E>, E: From<JsonError> {
+        self.try_encode(|js| {
+            js.begin_container(b'{');
+            js.check_error()?;
+            let (t, non_empty) = {
+                let mut jso = JsonObjectEncoder {js: js, needs_sep: false};
+                let t = f(&mut jso)?;
+                (t, jso.needs_sep)
+            };
+            js.end_container(b'}', non_empty);
+            Ok(t)
+        })
     }
 
+    /// Like `encode_array`, for closures that can fail, see `try_encode_obj`.
     #[inline]
-    pub fn element_with_value<T:JsonEncodable>(&mut self, val: T) { 
-        self.element().value(val);
+    pub fn try_encode_array<F, T, E>(&mut self, mut f: F) -> Result<T, E>
+        where F: FnMut(&mut JsonArrayEncoder<S>) -> Result<T, E>, E: From<JsonError> {
+        self.try_encode(|js| {
+            js.begin_container(b'[');
+            js.check_error()?;
+            let (t, non_empty) = {
+                let mut jsa = JsonArrayEncoder {js: js, needs_sep: false};
+                let t = f(&mut jsa)?;
+                (t, jsa.needs_sep)
+            };
+            js.end_container(b']', non_empty);
+            Ok(t)
+        })
     }
 
+    /// Runs `f`, and rolls back everything it wrote if it fails or leaves
+    /// an error recorded.
     #[inline]
-    pub fn end(self) {
-        self.js.buffer.push(b']');
-    }
-}
+    fn try_encode<F, T, E>(&mut self, f: F) -> Result<T, E>
+        where F: FnOnce(&mut JsonEncoder<S>) -> Result<T, E>, E: From<JsonError> {
+        let checkpoint = self.checkpoint();
 
+        self.rollback_points += 1;
+        let result = f(self).and_then(|t| self.check_error().map(|_| t));
+        self.rollback_points -= 1;
 
-pub struct JsonObjectEncoder<'a> {
-    js: &'a mut JsonEncoder,
-    needs_sep: bool,
-}
+        if result.is_err() {
+            self.restore(checkpoint);
+        }
+        result
+    }
 
-impl<'a> JsonObjectEncoder<'a> {
+    #[inline]
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            pos: self.buffer.get_current_position(),
+            depth: self.depth,
+            open_containers: self.open_positions.len(),
+            line_start: self.line_start,
+            error: self.error.clone(),
+            max_len: self.buffer.max_len,
+            full: self.buffer.full,
+            over_limit: self.buffer.over_limit,
+        }
+    }
 
+    /// Removes the output written since `checkpoint`, including containers
+    /// that were opened but not closed again, and errors recorded since.
     #[inline]
-    pub fn get_json_encoder<'b>(&'b mut self) -> &'b mut JsonEncoder {
-        self.js
+    fn restore(&mut self, checkpoint: Checkpoint) {
+        self.buffer.set_current_position(checkpoint.pos);
+        self.buffer.max_len = checkpoint.max_len;
+        self.buffer.full = checkpoint.full;
+        self.buffer.over_limit = checkpoint.over_limit;
+        self.error = checkpoint.error;
+        self.depth = checkpoint.depth;
+        self.open_positions.truncate(checkpoint.open_containers);
+        self.line_start = checkpoint.line_start;
+        self.release_snapshots();
+    }
+
+    /// Like `checkpoint`, for a snapshot, which keeps the output from being
+    /// drained while the current container is open.
+    #[inline]
+    fn snapshot_checkpoint(&self) -> Checkpoint {
+        self.snapshot_depth.set(match self.snapshot_depth.get() {
+            Some(depth) if depth < self.depth => Some(depth),
+            _ => Some(self.depth),
+        });
+        self.checkpoint()
     }
 
-    pub fn to_json_obj<'b>(&'b mut self) -> JsonObj<'b> {
-        if self.needs_sep {
-            JsonObj {js: self.js, elm_count: 1 /* XXX */}
-        } else {
-            JsonObj {js: self.js, elm_count: 0 /* XXX */}
+    /// Lets the output be drained again once the containers holding
+    /// snapshots are closed.
+    #[inline]
+    fn release_snapshots(&mut self) {
+        if let Some(depth) = self.snapshot_depth.get() {
+            if depth > self.depth {
+                self.snapshot_depth.set(None);
+            }
         }
     }
 
-    // XXX: name MAY NOT include escapable characters
     #[inline]
-    pub fn encode_field<F, T>(&mut self, name: &str, mut f: F) -> T where F: FnMut(&mut JsonEncoder) -> T {
-        if self.needs_sep {
-            self.js.buffer.push_all_around2(b",\"", name.as_bytes(), b"\":");
-        } else {
-            self.js.buffer.push_all_around2(b"\"", name.as_bytes(), b"\":");
-            self.needs_sep = true;
+    fn check_error<E: From<JsonError>>(&self) -> Result<(), E> {
+        match self.error() {
+            Some(err) => Err(E::from(err.clone())),
+            None => Ok(()),
         }
-        f(self.js)
     }
 
     #[inline]
-    pub fn encode_field_array<F, T>(&mut self, name: &str, mut f: F) -> T where F: FnMut(&mut JsonArrayEncoder) -> T {
-        self.encode_field(name, |js| js.encode_array(|jsa| f(jsa)))
+    pub fn encode_array_nobrackets<F, T>(&mut self, mut f: F) -> T where F: FnMut(&mut JsonArrayEncoder<S>) -> T {
+        f(&mut JsonArrayEncoder {js: self, needs_sep: false})
     }
 
+    /// Encodes an externally tagged enum variant: `{"<variant>":...}`,
+    /// where `f` writes the content.
     #[inline]
-    pub fn encode_field_obj<F, T>(&mut self, name: &str, mut f: F) -> T where F: FnMut(&mut JsonObjectEncoder) -> T {
-        self.encode_field(name, |js| js.encode_obj(|jso| f(jso)))
+    pub fn encode_externally_tagged<F, T>(&mut self, variant: &str, mut f: F) -> T where F: FnMut(&mut JsonEncoder<S>) -> T {
+        self.encode_obj(|jso| jso.encode_field(variant, |js| f(js)))
     }
 
+    /// Encodes an internally tagged enum variant: `{"<tag>":"<variant>",...}`,
+    /// where `f` writes the remaining fields.
     #[inline]
-    pub fn encode_field_i32(&mut self, name: &str, val: i32) {
-        self.encode_field(name, |js| js.encode_i32(val));
+    pub fn encode_internally_tagged<F, T>(&mut self, tag: &str, variant: &str, mut f: F) -> T where F: FnMut(&mut JsonObjectEncoder<S>) -> T {
+        self.encode_obj(|jso| {
+            jso.encode_field_str(tag, variant);
+            f(jso)
+        })
     }
 
+    /// Encodes an adjacently tagged enum variant:
+    /// `{"<tag>":"<variant>","<content>":...}`, where `f` writes the content.
     #[inline]
-    pub fn encode_field_str(&mut self, name: &str, s: &str) {
-        self.encode_field(name, |js| js.encode_str(s));
+    pub fn encode_adjacently_tagged<F, T>(&mut self, tag: &str, content: &str, variant: &str, mut f: F) -> T where F: FnMut(&mut JsonEncoder<S>) -> T {
+        self.encode_obj(|jso| {
+            jso.encode_field_str(tag, variant);
+            jso.encode_field(content, |js| f(js))
+        })
+    }
+
+    #[inline]
+    pub fn obj<'a>(&'a mut self) -> JsonObj<'a, S> {
+        JsonObj::open(self)
     }
 }
 
-pub struct JsonArrayEncoder<'a> {
-    js: &'a mut JsonEncoder,
-    needs_sep: bool,
+/// A field name escaped at compile time, see `json_key!`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JsonKey {
+    // `,"name":`, which contains the variant without separator from index 1
+    with_sep: &'static [u8],
+    // length of the name before escaping, for `Limits::max_str_len`
+    name_len: usize,
 }
 
-impl<'a> JsonArrayEncoder<'a> {
-    #[inline]
-    pub fn encode_elm<F, T>(&mut self, mut f: F) -> T where F: FnMut(&mut JsonEncoder) -> T {
-        if self.needs_sep {
-            self.js.buffer.push(b',');
-        } else {
-            self.needs_sep = true;
+impl JsonKey {
+    /// Takes the escaped `,"name":` and the length of the unescaped name,
+    /// as built by `json_key!`. Panics if `with_sep` is not of that form,
+    /// which fails the build when called in a constant.
+    #[doc(hidden)]
+    pub const fn from_escaped(with_sep: &'static [u8], name_len: usize) -> JsonKey {
+        let len = with_sep.len();
+        assert!(len >= 4 && with_sep[0] == b',' && with_sep[1] == b'"'
+                && with_sep[len - 2] == b'"' && with_sep[len - 1] == b':',
+                "a JsonKey must be of the form `,\"name\":`");
+        let mut i = 2;
+        while i < len - 2 {
+            let b = with_sep[i];
+            assert!(b != b'"' && b >= 0x20, "the name of a JsonKey must be escaped");
+            i += if b == b'\\' { 2 } else { 1 };
         }
-        f(self.js)
+        assert!(i == len - 2, "the name of a JsonKey must be escaped");
+        JsonKey {with_sep: with_sep, name_len: name_len}
     }
 
+    /// `,"name":`
     #[inline]
-    pub fn encode_elm_i32(&mut self, val: i32) {
-        self.encode_elm(|js| js.encode_i32(val));
+    pub fn with_sep(&self) -> &'static [u8] {
+        self.with_sep
     }
 
+    /// `"name":`
     #[inline]
-    pub fn encode_elm_str(&mut self, s: &str) {
-        self.encode_elm(|js| js.encode_str(s));
+    pub fn without_sep(&self) -> &'static [u8] {
+        &self.with_sep[1..]
     }
+}
 
-    #[inline]
-    pub fn encode_elm_obj<F, T>(&mut self, mut f: F) -> T where F: FnMut(&mut JsonObjectEncoder) -> T {
-        self.encode_elm(|js| js.encode_obj(|jso| f(jso)))
+/// Length of the escaped `,"name":` for `json_key!`.
+#[doc(hidden)]
+pub const fn escaped_key_len(name: &str) -> usize {
+    let bytes = name.as_bytes();
+    let mut len = 4;
+    let mut i = 0;
+    while i < bytes.len() {
+        len += match LUT[bytes[i] as usize] {
+            0 => 1,
+            b'u' => 6,
+            _ => 2,
+        };
+        i += 1;
     }
+    len
 }
 
+/// Builds the escaped `,"name":` for `json_key!`, escaping like
+/// `encode_str`.
+#[doc(hidden)]
+pub const fn escape_key<const N: usize>(name: &str) -> [u8; N] {
+    let bytes = name.as_bytes();
+    let mut out = [0u8; N];
+    out[0] = b',';
+    out[1] = b'"';
+    let mut w = 2;
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = bytes[i];
+        let escaped2 = LUT[byte as usize];
+        if escaped2 == 0 {
+            out[w] = byte;
+            w += 1;
+        } else {
+            out[w] = b'\\';
+            out[w + 1] = escaped2;
+            w += 2;
+            if escaped2 == b'u' {
+                out[w] = b'0';
+                out[w + 1] = b'0';
+                out[w + 2] = HEX_LOWER[(byte >> 4) as usize];
+                out[w + 3] = HEX_LOWER[(byte & 15) as usize];
+                w += 4;
+            }
+        }
+        i += 1;
+    }
+    out[w] = b'"';
+    out[w + 1] = b':';
+    out
+}
 
-#[test]
-fn test_json_obj_encoder() {
-    use std::str;
+/// Escapes a field name at compile time, for `JsonObj::key` and
+/// `JsonObjectEncoder::encode_key`. The name must be a constant
+/// expression.
+///
+/// ```
+/// #[macro_use] extern crate json_encoder;
+/// use json_encoder::{JsonEncoder, JsonKey};
+///
+/// const ID: JsonKey = json_key!("id");
+///
+/// # fn main() {
+/// let mut js = JsonEncoder::new();
+/// js.encode_obj(|jso| jso.encode_key(ID, |js| js.encode_u64(1)));
+/// assert_eq!(&b"{\"id\":1}"[..], &js.into_vec()[..]);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! json_key {
+    ($name:expr) => {{
+        const LEN: usize = $crate::escaped_key_len($name);
+        const ESCAPED: [u8; LEN] = $crate::escape_key::<LEN>($name);
+        const KEY: $crate::JsonKey = $crate::JsonKey::from_escaped(&ESCAPED, $name.len());
+        KEY
+    }};
+}
 
-    let mut js = JsonEncoder::new();
-    js.encode_obj(|_|{});
-    assert_eq!(b"{}", &js.into_vec()[..]);
+// Output position, layout and error state, see `JsonEncoder::restore`.
+struct Checkpoint {
+    pos: usize,
+    depth: usize,
+    open_containers: usize,
+    line_start: usize,
+    error: Option<JsonError>,
+    max_len: usize,
+    full: bool,
+    over_limit: bool,
+}
 
-    let mut js = JsonEncoder::new();
-    js.encode_obj(|jso| {
-        jso.encode_field("total", |js| js.encode_i32(31));
-    });
-    assert_eq!(b"{\"total\":31}", &js.into_vec()[..]);
+pub struct JsonObj<'a, S: 'a = Vec<u8>> {
+    js: &'a mut JsonEncoder<S>,
+    elm_count: usize,
+}
 
-    let mut js = JsonEncoder::new();
-    js.encode_obj(|jso| {
-        jso.encode_field("total", |js| js.encode_i32(31));
-        jso.encode_field("next", |js| js.encode_str("abc"));
-    });
-    let v = js.into_vec(); 
-    println!("{}", str::from_utf8(&v[..]).unwrap());
-    assert_eq!(b"{\"total\":31,\"next\":\"abc\"}", &v[..]);
+pub struct JsonVal<'a, S: 'a = Vec<u8>> {
+    js: &'a mut JsonEncoder<S>,
+}
 
-    let mut js = JsonEncoder::new();
-    js.encode_obj(|jso| {
-        jso.encode_field("total", |js| js.encode_i32(31));
-        jso.encode_field("next", |js| js.encode_str("abc"));
-        jso.encode_field("tags", |js| {
-                js.encode_array(|jsa| {
-                        jsa.encode_elm(|js| js.encode_i32(1));
-                        jsa.encode_elm(|js| js.encode_i32(2));
-                });
-        });
-    });
-    assert_eq!("{\"total\":31,\"next\":\"abc\",\"tags\":[1,2]}", str::from_utf8(&js.into_vec()[..]).unwrap());
+pub struct JsonVec<'a, S: 'a = Vec<u8>> {
+    js: &'a mut JsonEncoder<S>,
+    elm_count: usize,
+}
 
-    let json = JsonEncoder::obj_single_str_field("total", "abcdef");
-    assert_eq!(b"{\"total\":\"abcdef\"}", &json[..]);
+pub trait JsonEncodable {
+    fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>);
+}
 
-    let json = JsonEncoder::obj_single_str_field("total", "");
-    assert_eq!(b"{\"total\":\"\"}", &json[..]);
+/// Encodes `self` as the fields of an already opened object. Implemented by
+/// `#[derive(JsonEncodable)]` for structs with named fields, and used for
+/// `#[json(flatten)]`.
+pub trait JsonEncodableFields {
+    fn encode_fields<S: Sink>(&self, jso: &mut JsonObjectEncoder<S>);
 }
 
-#[test]
-fn test_json_empty_obj() {
-    let mut js = JsonEncoder::new();
-    {
-        let obj = js.obj();
-        obj.end();
+impl JsonEncodable for str {
+    #[inline]
+    fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>) {
+        js.encode_str(self);
     }
+}
 
-    assert_eq!(b"{}", &js.into_vec()[..]);
+impl JsonEncodable for String {
+    #[inline]
+    fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>) {
+        js.encode_str(self);
+    }
 }
 
-#[test]
-fn test_json_single_field() {
-    let mut js = JsonEncoder::new();
-    {
-        let mut obj = js.obj();
-        obj.field("name").value("hallo");
-        obj.end();
-        // XXX: checkpoint
+macro_rules! impl_encodable {
+    ($($t:ty => $method:ident),*) => {
+        $(
+            impl JsonEncodable for $t {
+                #[inline]
+                fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>) {
+                    js.$method(*self);
+                }
+            }
+        )*
     }
+}
 
-    assert_eq!(b"{\"name\":\"hallo\"}", &js.into_vec()[..]);
+impl_encodable!(bool => encode_bool,
+                i32 => encode_i32, u32 => encode_u32,
+                i64 => encode_i64, u64 => encode_u64,
+                i128 => encode_i128, u128 => encode_u128,
+                isize => encode_isize, usize => encode_usize);
+
+impl JsonEncodable for f64 {
+    #[inline]
+    fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>) {
+        js.encode_f64(*self);
+    }
 }
 
-#[test]
-fn test_json_two_fields() {
-    let mut js = JsonEncoder::new();
-    {
-        let mut obj = js.obj();
-        obj.field("name").value("hallo");
-        obj.field("i").value(123_i32);
-        obj.end();
+impl JsonEncodable for f32 {
+    #[inline]
+    fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>) {
+        js.encode_f32(*self);
     }
+}
 
-    assert_eq!(b"{\"name\":\"hallo\",\"i\":123}", &js.into_vec()[..]);
+impl JsonEncodable for () {
+    #[inline]
+    fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>) {
+        js.encode_null();
+    }
+}
+
+impl<'a, T: JsonEncodable + ?Sized> JsonEncodable for &'a T {
+    #[inline]
+    fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>) {
+        (**self).encode(js);
+    }
+}
+
+impl<'a, T: JsonEncodable + ?Sized> JsonEncodable for &'a mut T {
+    #[inline]
+    fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>) {
+        (**self).encode(js);
+    }
+}
+
+impl<T: JsonEncodable + ?Sized> JsonEncodable for Box<T> {
+    #[inline]
+    fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>) {
+        (**self).encode(js);
+    }
+}
+
+impl<T: JsonEncodable + ?Sized> JsonEncodable for Rc<T> {
+    #[inline]
+    fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>) {
+        (**self).encode(js);
+    }
+}
+
+impl<T: JsonEncodable + ?Sized> JsonEncodable for Arc<T> {
+    #[inline]
+    fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>) {
+        (**self).encode(js);
+    }
+}
+
+impl<'a, B: JsonEncodable + ToOwned + ?Sized> JsonEncodable for Cow<'a, B> {
+    #[inline]
+    fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>) {
+        (**self).encode(js);
+    }
+}
+
+/// `None` is encoded as `null`.
+impl<T: JsonEncodable> JsonEncodable for Option<T> {
+    #[inline]
+    fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>) {
+        match *self {
+            Some(ref val) => val.encode(js),
+            None => js.encode_null(),
+        }
+    }
+}
+
+impl<T: JsonEncodable> JsonEncodable for [T] {
+    #[inline]
+    fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>) {
+        js.encode_array(|jsa| {
+            for elm in self.iter() {
+                jsa.encode_elm(|js| elm.encode(js));
+            }
+        });
+    }
+}
+
+impl<T: JsonEncodable, const N: usize> JsonEncodable for [T; N] {
+    #[inline]
+    fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>) {
+        self[..].encode(js);
+    }
+}
+
+impl<T: JsonEncodable> JsonEncodable for Vec<T> {
+    #[inline]
+    fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>) {
+        self[..].encode(js);
+    }
+}
+
+macro_rules! impl_encodable_tuple {
+    ($($name:ident)+) => {
+        impl<$($name: JsonEncodable),+> JsonEncodable for ($($name,)+) {
+            #[inline]
+            #[allow(non_snake_case)]
+            fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>) {
+                let ($(ref $name,)+) = *self;
+                js.encode_array(|jsa| {
+                    $(jsa.encode_elm(|js| $name.encode(js));)+
+                });
+            }
+        }
+    }
+}
+
+impl_encodable_tuple!(A);
+impl_encodable_tuple!(A B);
+impl_encodable_tuple!(A B C);
+impl_encodable_tuple!(A B C D);
+impl_encodable_tuple!(A B C D E);
+impl_encodable_tuple!(A B C D E F);
+impl_encodable_tuple!(A B C D E F G);
+impl_encodable_tuple!(A B C D E F G H);
+impl_encodable_tuple!(A B C D E F G H I);
+impl_encodable_tuple!(A B C D E F G H I J);
+impl_encodable_tuple!(A B C D E F G H I J K);
+impl_encodable_tuple!(A B C D E F G H I J K L);
+
+/// Maps with string-like keys are encoded as objects.
+#[cfg(feature = "std")]
+impl<K, V, H> JsonEncodable for HashMap<K, V, H>
+    where K: AsRef<str> + Eq + Hash, V: JsonEncodable, H: BuildHasher
+{
+    #[inline]
+    fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>) {
+        js.encode_obj(|jso| self.encode_fields(jso));
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K, V, H> JsonEncodableFields for HashMap<K, V, H>
+    where K: AsRef<str> + Eq + Hash, V: JsonEncodable, H: BuildHasher
+{
+    #[inline]
+    fn encode_fields<S: Sink>(&self, jso: &mut JsonObjectEncoder<S>) {
+        for (key, val) in self.iter() {
+            jso.encode_field(key.as_ref(), |js| val.encode(js));
+        }
+    }
+}
+
+/// Maps with string-like keys are encoded as objects.
+impl<K, V> JsonEncodable for BTreeMap<K, V>
+    where K: AsRef<str> + Ord, V: JsonEncodable
+{
+    #[inline]
+    fn encode<S: Sink>(&self, js: &mut JsonEncoder<S>) {
+        js.encode_obj(|jso| self.encode_fields(jso));
+    }
+}
+
+impl<K, V> JsonEncodableFields for BTreeMap<K, V>
+    where K: AsRef<str> + Ord, V: JsonEncodable
+{
+    #[inline]
+    fn encode_fields<S: Sink>(&self, jso: &mut JsonObjectEncoder<S>) {
+        for (key, val) in self.iter() {
+            jso.encode_field(key.as_ref(), |js| val.encode(js));
+        }
+    }
+}
+
+impl<'a, S: Sink> JsonVal<'a, S> {
+    #[inline]
+    pub fn value<T:JsonEncodable>(self, val: T) {
+        val.encode(self.js);
+    }
+
+    #[inline]
+    pub fn null(self) {
+        self.js.encode_null();
+    }
+
+    #[inline]
+    pub fn obj(self) -> JsonObj<'a, S> {
+        JsonObj::open(self.js)
+    }
+
+    #[inline]
+    pub fn vec(self) -> JsonVec<'a, S> {
+        JsonVec::open(self.js)
+    }
+}
+
+impl<'a, S: Sink> JsonObj<'a, S> {
+    #[inline]
+    fn open<'b>(js: &'b mut JsonEncoder<S>) -> JsonObj<'b, S> {
+        js.begin_container(b'{');
+        JsonObj {js: js, elm_count: 0}
+    }
+
+    #[inline]
+    pub fn field<'b>(&'b mut self, name: &str) -> JsonVal<'b, S> {
+        self.js.begin_field(self.elm_count == 0, name.as_bytes());
+        self.elm_count += 1;
+        JsonVal {js: self.js} 
+    }
+
+    /// Like `field`, with a name escaped beforehand.
+    #[inline]
+    pub fn key<'b>(&'b mut self, key: JsonKey) -> JsonVal<'b, S> {
+        self.js.begin_field_key(self.elm_count == 0, key);
+        self.elm_count += 1;
+        JsonVal {js: self.js}
+    }
+
+    /// Like `field`, but writes `name` without escaping it. It must not
+    /// contain quotes, backslashes or control characters.
+    #[inline]
+    pub fn field_unescaped<'b>(&'b mut self, name: &str) -> JsonVal<'b, S> {
+        self.js.begin_field_raw(self.elm_count == 0, name.as_bytes());
+        self.elm_count += 1;
+        JsonVal {js: self.js}
+    }
+
+    /// Marks the current end of the object, to drop the fields written
+    /// after it with `rollback`.
+    #[inline]
+    pub fn snapshot(&self) -> JsonObjSnapshot {
+        JsonObjSnapshot {
+            elm_count: self.elm_count,
+            checkpoint: self.js.snapshot_checkpoint(),
+        }
+    }
+
+    #[inline]
+    pub fn rollback(&mut self, snapshot: JsonObjSnapshot) {
+        self.elm_count = snapshot.elm_count;
+        self.js.restore(snapshot.checkpoint);
+    }
+
+    #[inline]
+    pub fn end(self) {
+        self.js.end_container(b'}', self.elm_count > 0);
+    }
+}
+
+/// Marks a point in an object to roll back to, see `JsonObj::snapshot` and
+/// `JsonObjectEncoder::snapshot`. A streaming sink holds back its output
+/// until the object is closed.
+pub struct JsonObjSnapshot {
+    elm_count: usize,
+    checkpoint: Checkpoint,
+}
+
+
+/// Marks a point in an array to roll back to, see `JsonVec::snapshot`.
+/// A streaming sink holds back its output until the array is closed.
+pub struct JsonVecSnapshot {
+    elm_count: usize,
+    checkpoint: Checkpoint,
+}
+
+impl<'a, S: Sink> JsonVec<'a, S> {
+    #[inline]
+    fn open<'b>(js: &'b mut JsonEncoder<S>) -> JsonVec<'b, S> {
+        js.begin_container(b'[');
+        JsonVec {js: js, elm_count: 0}
+    }
+
+    #[inline]
+    pub fn snapshot(&self) -> JsonVecSnapshot {
+        JsonVecSnapshot {
+            elm_count: self.elm_count,
+            checkpoint: self.js.snapshot_checkpoint(),
+        }
+    }
+
+    #[inline]
+    pub fn rollback(&mut self, snapshot: JsonVecSnapshot) {
+        self.elm_count = snapshot.elm_count;
+        self.js.restore(snapshot.checkpoint);
+    }
+
+    #[inline]
+    pub fn element<'b>(&'b mut self) -> JsonVal<'b, S> {
+        self.js.begin_element(self.elm_count == 0);
+        self.elm_count += 1;
+        JsonVal {js: self.js} 
+    }
+
+    #[inline]
+    pub fn element_with_value<T:JsonEncodable>(&mut self, val: T) { 
+        self.element().value(val);
+    }
+
+    #[inline]
+    pub fn end(self) {
+        self.js.end_container(b']', self.elm_count > 0);
+    }
+}
+
+
+pub struct JsonObjectEncoder<'a, S: 'a = Vec<u8>> {
+    js: &'a mut JsonEncoder<S>,
+    needs_sep: bool,
+}
+
+impl<'a, S: Sink> JsonObjectEncoder<'a, S> {
+
+    #[inline]
+    pub fn get_json_encoder<'b>(&'b mut self) -> &'b mut JsonEncoder<S> {
+        self.js
+    }
+
+    pub fn to_json_obj<'b>(&'b mut self) -> JsonObj<'b, S> {
+        if self.needs_sep {
+            JsonObj {js: self.js, elm_count: 1 /* XXX */}
+        } else {
+            JsonObj {js: self.js, elm_count: 0 /* XXX */}
+        }
+    }
+
+    /// Like `JsonObj::snapshot`.
+    #[inline]
+    pub fn snapshot(&self) -> JsonObjSnapshot {
+        JsonObjSnapshot {
+            elm_count: self.needs_sep as usize,
+            checkpoint: self.js.snapshot_checkpoint(),
+        }
+    }
+
+    #[inline]
+    pub fn rollback(&mut self, snapshot: JsonObjSnapshot) {
+        self.needs_sep = snapshot.elm_count > 0;
+        self.js.restore(snapshot.checkpoint);
+    }
+
+    #[inline]
+    pub fn encode_field<F, T>(&mut self, name: &str, mut f: F) -> T where F: FnMut(&mut JsonEncoder<S>) -> T {
+        self.js.begin_field(!self.needs_sep, name.as_bytes());
+        self.needs_sep = true;
+        f(self.js)
+    }
+
+    /// Like `encode_field`, but writes `name` without escaping it. It must
+    /// not contain quotes, backslashes or control characters.
+    #[inline]
+    pub fn encode_field_raw<F, T>(&mut self, name: &str, mut f: F) -> T where F: FnMut(&mut JsonEncoder<S>) -> T {
+        self.js.begin_field_raw(!self.needs_sep, name.as_bytes());
+        self.needs_sep = true;
+        f(self.js)
+    }
+
+    /// Like `encode_field`, for closures that can fail. On error, including
+    /// an exceeded limit, the field is removed from the output again and
+    /// the error is returned.
+    #[inline]
+    pub fn try_encode_field<F, T, E>(&mut self, name: &str, mut f: F) -> Result<T, E>
+        where F: FnMut(&mut JsonEncoder<S>) -> Result<T, E>, E: From<JsonError> {
+        let first = !self.needs_sep;
+        let t = self.js.try_encode(|js| {
+            js.begin_field(first, name.as_bytes());
+            f(js)
+        })?;
+        self.needs_sep = true;
+        Ok(t)
+    }
+
+    /// Like `encode_field`, with a name escaped beforehand.
+    #[inline]
+    pub fn encode_key<F, T>(&mut self, key: JsonKey, mut f: F) -> T where F: FnMut(&mut JsonEncoder<S>) -> T {
+        self.js.begin_field_key(!self.needs_sep, key);
+        self.needs_sep = true;
+        f(self.js)
+    }
+
+    #[inline]
+    pub fn encode_field_array<F, T>(&mut self, name: &str, mut f: F) -> T where F: FnMut(&mut JsonArrayEncoder<S>) -> T {
+        self.encode_field(name, |js| js.encode_array(|jsa| f(jsa)))
+    }
+
+    #[inline]
+    pub fn encode_field_obj<F, T>(&mut self, name: &str, mut f: F) -> T where F: FnMut(&mut JsonObjectEncoder<S>) -> T {
+        self.encode_field(name, |js| js.encode_obj(|jso| f(jso)))
+    }
+
+    #[inline]
+    pub fn encode_field_i32(&mut self, name: &str, val: i32) {
+        self.encode_field(name, |js| js.encode_i32(val));
+    }
+
+    #[inline]
+    pub fn encode_field_bool(&mut self, name: &str, val: bool) {
+        self.encode_field(name, |js| js.encode_bool(val));
+    }
+
+    #[inline]
+    pub fn encode_field_null(&mut self, name: &str) {
+        self.encode_field(name, |js| js.encode_null());
+    }
+
+    #[inline]
+    pub fn encode_field_u64(&mut self, name: &str, val: u64) {
+        self.encode_field(name, |js| js.encode_u64(val));
+    }
+
+    #[inline]
+    pub fn encode_field_i64(&mut self, name: &str, val: i64) {
+        self.encode_field(name, |js| js.encode_i64(val));
+    }
+
+    #[inline]
+    pub fn encode_field_u128(&mut self, name: &str, val: u128) {
+        self.encode_field(name, |js| js.encode_u128(val));
+    }
+
+    #[inline]
+    pub fn encode_field_i128(&mut self, name: &str, val: i128) {
+        self.encode_field(name, |js| js.encode_i128(val));
+    }
+
+    #[inline]
+    pub fn encode_field_usize(&mut self, name: &str, val: usize) {
+        self.encode_field(name, |js| js.encode_usize(val));
+    }
+
+    #[inline]
+    pub fn encode_field_isize(&mut self, name: &str, val: isize) {
+        self.encode_field(name, |js| js.encode_isize(val));
+    }
+
+    #[inline]
+    pub fn encode_field_f64(&mut self, name: &str, val: f64) {
+        self.encode_field(name, |js| js.encode_f64(val));
+    }
+
+    #[inline]
+    pub fn encode_field_f32(&mut self, name: &str, val: f32) {
+        self.encode_field(name, |js| js.encode_f32(val));
+    }
+
+    #[inline]
+    pub fn encode_field_str(&mut self, name: &str, s: &str) {
+        self.encode_field(name, |js| js.encode_str(s));
+    }
+}
+
+pub struct JsonArrayEncoder<'a, S: 'a = Vec<u8>> {
+    js: &'a mut JsonEncoder<S>,
+    needs_sep: bool,
+}
+
+impl<'a, S: Sink> JsonArrayEncoder<'a, S> {
+    #[inline]
+    pub fn encode_elm<F, T>(&mut self, mut f: F) -> T where F: FnMut(&mut JsonEncoder<S>) -> T {
+        self.js.begin_element(!self.needs_sep);
+        self.needs_sep = true;
+        f(self.js)
+    }
+
+    /// Like `encode_elm`, for closures that can fail. On error, including
+    /// an exceeded limit, the element is removed from the output again and
+    /// the error is returned.
+    #[inline]
+    pub fn try_encode_elm<F, T, E>(&mut self, mut f: F) -> Result<T, E>
+        where F: FnMut(&mut JsonEncoder<S>) -> Result<T, E>, E: From<JsonError> {
+        let first = !self.needs_sep;
+        let t = self.js.try_encode(|js| {
+            js.begin_element(first);
+            f(js)
+        })?;
+        self.needs_sep = true;
+        Ok(t)
+    }
+
+    #[inline]
+    pub fn encode_elm_i32(&mut self, val: i32) {
+        self.encode_elm(|js| js.encode_i32(val));
+    }
+
+    #[inline]
+    pub fn encode_elm_bool(&mut self, val: bool) {
+        self.encode_elm(|js| js.encode_bool(val));
+    }
+
+    #[inline]
+    pub fn encode_elm_null(&mut self) {
+        self.encode_elm(|js| js.encode_null());
+    }
+
+    #[inline]
+    pub fn encode_elm_u64(&mut self, val: u64) {
+        self.encode_elm(|js| js.encode_u64(val));
+    }
+
+    #[inline]
+    pub fn encode_elm_i64(&mut self, val: i64) {
+        self.encode_elm(|js| js.encode_i64(val));
+    }
+
+    #[inline]
+    pub fn encode_elm_u128(&mut self, val: u128) {
+        self.encode_elm(|js| js.encode_u128(val));
+    }
+
+    #[inline]
+    pub fn encode_elm_i128(&mut self, val: i128) {
+        self.encode_elm(|js| js.encode_i128(val));
+    }
+
+    #[inline]
+    pub fn encode_elm_usize(&mut self, val: usize) {
+        self.encode_elm(|js| js.encode_usize(val));
+    }
+
+    #[inline]
+    pub fn encode_elm_isize(&mut self, val: isize) {
+        self.encode_elm(|js| js.encode_isize(val));
+    }
+
+    #[inline]
+    pub fn encode_elm_f64(&mut self, val: f64) {
+        self.encode_elm(|js| js.encode_f64(val));
+    }
+
+    #[inline]
+    pub fn encode_elm_f32(&mut self, val: f32) {
+        self.encode_elm(|js| js.encode_f32(val));
+    }
+
+    #[inline]
+    pub fn encode_elm_str(&mut self, s: &str) {
+        self.encode_elm(|js| js.encode_str(s));
+    }
+
+    #[inline]
+    pub fn encode_elm_obj<F, T>(&mut self, mut f: F) -> T where F: FnMut(&mut JsonObjectEncoder<S>) -> T {
+        self.encode_elm(|js| js.encode_obj(|jso| f(jso)))
+    }
+}
+
+
+#[test]
+fn test_json_obj_encoder() {
+    use std::str;
+
+    let mut js = JsonEncoder::new();
+    js.encode_obj(|_|{});
+    assert_eq!(b"{}", &js.into_vec()[..]);
+
+    let mut js = JsonEncoder::new();
+    js.encode_obj(|jso| {
+        jso.encode_field("total", |js| js.encode_i32(31));
+    });
+    assert_eq!(b"{\"total\":31}", &js.into_vec()[..]);
+
+    let mut js = JsonEncoder::new();
+    js.encode_obj(|jso| {
+        jso.encode_field("total", |js| js.encode_i32(31));
+        jso.encode_field("next", |js| js.encode_str("abc"));
+    });
+    let v = js.into_vec(); 
+    println!("{}", str::from_utf8(&v[..]).unwrap());
+    assert_eq!(b"{\"total\":31,\"next\":\"abc\"}", &v[..]);
+
+    let mut js = JsonEncoder::new();
+    js.encode_obj(|jso| {
+        jso.encode_field("total", |js| js.encode_i32(31));
+        jso.encode_field("next", |js| js.encode_str("abc"));
+        jso.encode_field("tags", |js| {
+                js.encode_array(|jsa| {
+                        jsa.encode_elm(|js| js.encode_i32(1));
+                        jsa.encode_elm(|js| js.encode_i32(2));
+                });
+        });
+    });
+    assert_eq!("{\"total\":31,\"next\":\"abc\",\"tags\":[1,2]}", str::from_utf8(&js.into_vec()[..]).unwrap());
+
+    let json = JsonEncoder::obj_single_str_field("total", "abcdef");
+    assert_eq!(b"{\"total\":\"abcdef\"}", &json[..]);
+
+    let json = JsonEncoder::obj_single_str_field("total", "");
+    assert_eq!(b"{\"total\":\"\"}", &json[..]);
+}
+
+#[test]
+fn test_json_empty_obj() {
+    let mut js = JsonEncoder::new();
+    {
+        let obj = js.obj();
+        obj.end();
+    }
+
+    assert_eq!(b"{}", &js.into_vec()[..]);
+}
+
+#[test]
+fn test_json_single_field() {
+    let mut js = JsonEncoder::new();
+    {
+        let mut obj = js.obj();
+        obj.field("name").value("hallo");
+        let snapshot = obj.snapshot();
+        obj.field("omitted").obj().end();
+        obj.rollback(snapshot);
+        obj.end();
+    }
+
+    assert_eq!(b"{\"name\":\"hallo\"}", &js.into_vec()[..]);
+}
+
+#[test]
+fn test_json_obj_snapshot() {
+    let mut js = JsonEncoder::new();
+    js.set_pretty(Some(PrettyConfig {line_width: Some(80), ..PrettyConfig::default()}));
+    {
+        let mut obj = js.obj();
+        {
+            let mut inner = obj.field("inner").obj();
+            inner.field("a").value(1_i32);
+            let snapshot = inner.snapshot();
+            inner.field("b").vec().element_with_value(2_i32);
+            inner.rollback(snapshot);
+            inner.end();
+        }
+        let kept = obj.snapshot();
+        {
+            // left open, which the rollback undoes as well
+            let mut open = obj.field("open").obj();
+            open.field("c").value(3_i32);
+        }
+        obj.rollback(kept);
+        obj.field("x").value(4_i32);
+        obj.end();
+    }
+    assert_eq!("{\"inner\": {\"a\": 1}, \"x\": 4}", String::from_utf8(js.into_vec()).unwrap());
+
+    let mut js = JsonEncoder::new();
+    js.encode_obj(|jso| {
+        let empty = jso.snapshot();
+        jso.encode_field_obj("optional", |jso| jso.encode_field_null("a"));
+        jso.rollback(empty);
+        jso.encode_field_i32("first", 1);
+        let snapshot = jso.snapshot();
+        jso.encode_field_i32("second", 2);
+        jso.rollback(snapshot);
+    });
+    assert_eq!("{\"first\":1}", String::from_utf8(js.into_vec()).unwrap());
+}
+
+#[test]
+fn test_json_field_escaping() {
+    let mut js = JsonEncoder::new();
+    {
+        let mut obj = js.obj();
+        obj.field("a\"b").value(1_i32);
+        obj.field("\\\n\u{1}").value(2_i32);
+        obj.field_unescaped("plain").value(3_i32);
+        obj.end();
+    }
+    assert_eq!("{\"a\\\"b\":1,\"\\\\\\n\\u0001\":2,\"plain\":3}", String::from_utf8(js.into_vec()).unwrap());
+
+    let mut map = BTreeMap::new();
+    map.insert("}\"", 1_i32);
+    let mut js = JsonEncoder::new();
+    js.set_pretty(Some(PrettyConfig::default()));
+    js.encode_obj(|jso| {
+        jso.encode_field_raw("raw", |js| js.encode_null());
+        map.encode_fields(jso);
+    });
+    assert_eq!("{\n  \"raw\": null,\n  \"}\\\"\": 1\n}", String::from_utf8(js.into_vec()).unwrap());
+}
+
+#[test]
+fn test_json_keys() {
+    const NAME: JsonKey = json_key!("name");
+    const ODD: JsonKey = json_key!("a\"\\\t\u{1f}\u{e9}");
+
+    assert_eq!(&b",\"name\":"[..], NAME.with_sep());
+    assert_eq!(&b"\"name\":"[..], NAME.without_sep());
+    assert_eq!(&b",\"a\\\"\\\\\\t\\u001f\xc3\xa9\":"[..], ODD.with_sep());
+
+    let mut js = JsonEncoder::new();
+    {
+        let mut obj = js.obj();
+        obj.key(NAME).value("hallo");
+        obj.key(ODD).value(1_i32);
+        obj.end();
+    }
+    js.encode_obj(|jso| {
+        jso.encode_key(ODD, |js| js.encode_null());
+        jso.encode_key(NAME, |js| js.encode_bool(true));
+    });
+    assert_eq!("{\"name\":\"hallo\",\"a\\\"\\\\\\t\\u001f\u{e9}\":1}{\"a\\\"\\\\\\t\\u001f\u{e9}\":null,\"name\":true}",
+               String::from_utf8(js.into_vec()).unwrap());
+
+    let mut js = JsonEncoder::new();
+    js.set_pretty(Some(PrettyConfig::default()));
+    js.encode_obj(|jso| {
+        jso.encode_key(NAME, |js| js.encode_i32(1));
+        jso.encode_key(NAME, |js| js.encode_i32(2));
+    });
+    assert_eq!("{\n  \"name\": 1,\n  \"name\": 2\n}", String::from_utf8(js.into_vec()).unwrap());
+
+    // the limit applies to the unescaped name
+    for &pretty in &[None, Some(PrettyConfig::default())] {
+        let enc = |max_str_len| {
+            let mut js = JsonEncoder::new();
+            js.set_pretty(pretty);
+            js.set_limits(Limits {max_str_len: Some(max_str_len), ..Limits::default()});
+            js.encode_obj(|jso| jso.encode_key(NAME, |js| js.encode_null()));
+            js.finish().map(|_| ())
+        };
+        assert_eq!(Ok(()), enc(4));
+        assert_eq!(Err(JsonError::StrTooLong), enc(3));
+    }
+}
+
+#[test]
+#[should_panic(expected = "a JsonKey must be of the form")]
+fn test_json_key_unquoted() {
+    JsonKey::from_escaped(b",name:", 4);
+}
+
+#[test]
+#[should_panic(expected = "the name of a JsonKey must be escaped")]
+fn test_json_key_unescaped() {
+    JsonKey::from_escaped(b",\"a\":1,\"b\":", 6);
+}
+
+#[test]
+fn test_json_two_fields() {
+    let mut js = JsonEncoder::new();
+    {
+        let mut obj = js.obj();
+        obj.field("name").value("hallo");
+        obj.field("i").value(123_i32);
+        obj.end();
+    }
+
+    assert_eq!(b"{\"name\":\"hallo\",\"i\":123}", &js.into_vec()[..]);
 }
 
 #[test]
@@ -808,144 +2664,792 @@ fn test_json_recursive_fields_with_vec() {
 
 
 
-#[bench]
-fn bench_encode_i32(b: &mut test::Bencher) {
-    let mut js = JsonEncoder::with_capacity(80);
-    b.iter(|| {
-        let n = test::black_box(10000);
-        for _ in (0..n) {
-            js.clear();
-            js.encode_i32(123_456_789);
-            js.encode_i32(321_654_987);
-            js.encode_i32(231_564_857);
-            js.encode_i32(189_123_456);
-        }
-    });
+#[test]
+fn test_encode_u32() {
+    let mut js = JsonEncoder::new();
+    js.encode_u32(0);
+    let vec = js.into_vec();
+    assert_eq!(b"0", &vec[..]);
+
+    let mut js = JsonEncoder::new();
+    js.encode_u32(123000);
+    let vec = js.into_vec();
+    assert_eq!(b"123000", &vec[..]);
+
+    let mut js = JsonEncoder::new();
+    js.encode_u32(1230001);
+    let vec = js.into_vec();
+    assert_eq!(b"1230001", &vec[..]);
 }
 
-#[bench]
-fn bench_encode_u32(b: &mut test::Bencher) {
-    let mut js = JsonEncoder::with_capacity(80);
-    b.iter(|| {
-        let n = test::black_box(10000);
-        for _ in (0..n) {
-            js.clear();
-            js.encode_u32(123_456_789);
-            js.encode_u32(321_654_987);
-            js.encode_u32(231_564_857);
-            js.encode_u32(189_123_456);
+#[test]
+fn test_encode_str_control_chars() {
+    let s: String = (0u8..0x80).map(|b| b as char).collect();
+    let mut expected = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => expected.push_str("\\\""),
+            '\\' => expected.push_str("\\\\"),
+            '\x08' => expected.push_str("\\b"),
+            '\x0c' => expected.push_str("\\f"),
+            '\n' => expected.push_str("\\n"),
+            '\r' => expected.push_str("\\r"),
+            '\t' => expected.push_str("\\t"),
+            c if c < ' ' => expected.push_str(&format!("\\u{:04x}", c as u32)),
+            c => expected.push(c),
         }
-    });
+    }
+    expected.push('"');
+
+    let encoders: [fn(&mut JsonEncoder, &str); 4] = [
+        JsonEncoder::encode_str,
+        JsonEncoder::encode_str_,
+        JsonEncoder::encode_str2,
+        JsonEncoder::encode_str3,
+    ];
+    for encode in encoders.iter() {
+        let mut js = JsonEncoder::new();
+        encode(&mut js, &s);
+        assert_eq!(expected.as_bytes(), &js.into_vec()[..]);
+    }
+
+    let mut js = JsonEncoder::new();
+    js.encode_str("\x00\x1b[0m\x1f");
+    assert_eq!(&b"\"\\u0000\\u001b[0m\\u001f\""[..], &js.into_vec()[..]);
 }
 
-#[bench]
-fn bench_encode_hex_u32(b: &mut test::Bencher) {
-    let mut js = JsonEncoder::with_capacity(80);
-    b.iter(|| {
-        let n = test::black_box(10000);
-        for _ in (0..n) {
-            js.clear();
-            js.encode_hex_u32(123_456_789);
-            js.encode_hex_u32(321_654_987);
-            js.encode_hex_u32(231_564_857);
-            js.encode_hex_u32(189_123_456);
-        }
+#[test]
+fn test_encode_f64() {
+    fn enc(value: f64) -> String {
+        let mut js = JsonEncoder::new();
+        js.encode_f64(value);
+        String::from_utf8(js.into_vec()).unwrap()
+    }
+
+    assert_eq!("0.0", enc(0.0));
+    assert_eq!("-0.0", enc(-0.0));
+    assert_eq!("1.5", enc(1.5));
+    assert_eq!("0.1", enc(0.1));
+    assert_eq!("1e300", enc(1e300));
+    assert_eq!("null", enc(f64::NAN));
+    assert_eq!("null", enc(f64::INFINITY));
+
+    for &v in [::std::f64::consts::PI, 5e-324, f64::MAX, f64::MIN, 1.0 / 3.0].iter() {
+        assert_eq!(v, enc(v).parse::<f64>().unwrap());
+    }
+
+    let mut js = JsonEncoder::new();
+    js.encode_f32(0.1);
+    assert_eq!(b"0.1", &js.into_vec()[..]);
+
+    let mut js = JsonEncoder::new();
+    js.encode_obj(|jso| {
+        jso.encode_field_f64("x", 0.5);
+        jso.encode_field_array("v", |jsa| {
+            jsa.encode_elm_f64(1.0);
+            jsa.encode_elm_f32(-2.5);
+        });
     });
+    assert_eq!(&b"{\"x\":0.5,\"v\":[1.0,-2.5]}"[..], &js.into_vec()[..]);
+
+    // without room for the longest possible output, floats are written exactly
+    let mut buf = [0u8; 4];
+    let mut js = JsonEncoder::with_sink(SliceSink::new(&mut buf));
+    js.encode_f64(0.25);
+    assert_eq!(b"0.25", js.finish().unwrap().written());
 }
 
-#[bench]
-fn bench_encode_hex_u32_fast(b: &mut test::Bencher) {
-    let mut js = JsonEncoder::with_capacity(80);
-    b.iter(|| {
-        let n = test::black_box(10000);
-        for _ in (0..n) {
-            js.clear();
-            js.encode_hex_u32_fast(123_456_789);
-            js.encode_hex_u32_fast(321_654_987);
-            js.encode_hex_u32_fast(231_564_857);
-            js.encode_hex_u32_fast(189_123_456);
-        }
+#[test]
+fn test_encode_non_finite() {
+    fn enc(policy: NonFinite) -> Vec<u8> {
+        let mut js = JsonEncoder::new();
+        js.set_non_finite(policy);
+        js.encode_array(|jsa| {
+            jsa.encode_elm_f64(f64::NAN);
+            jsa.encode_elm_f64(f64::INFINITY);
+            jsa.encode_elm_f32(f32::NEG_INFINITY);
+        });
+        js.finish().unwrap()
+    }
+
+    assert_eq!(&b"[null,null,null]"[..], &enc(NonFinite::Null)[..]);
+    assert_eq!(&b"[\"NaN\",\"Infinity\",\"-Infinity\"]"[..], &enc(NonFinite::Str)[..]);
+    assert_eq!(&b"[NaN,Infinity,-Infinity]"[..], &enc(NonFinite::Literal)[..]);
+
+    let mut js = JsonEncoder::new();
+    js.set_non_finite(NonFinite::Error);
+    assert_eq!(Ok(()), js.try_encode_f64(1.5));
+    assert_eq!(Err(JsonError::NonFiniteFloat), js.try_encode_f64(f64::NEG_INFINITY));
+    assert!(js.error().is_none());
+    js.encode_f64(f64::NAN);
+    assert_eq!(Some(&JsonError::NonFiniteFloat), js.error());
+    assert_eq!(Err(JsonError::NonFiniteFloat), js.finish());
+}
+
+#[test]
+fn test_encode_u64() {
+    fn enc<F>(f: F) -> String where F: Fn(&mut JsonEncoder) {
+        let mut js = JsonEncoder::new();
+        f(&mut js);
+        String::from_utf8(js.into_vec()).unwrap()
+    }
+
+    for &v in [0, 7, 10, 99, 100, 101, 1_000_000, 12_345_678_901_234_567_890].iter() {
+        assert_eq!(v.to_string(), enc(|js| js.encode_u64(v)));
+        assert_eq!(v.to_string(), enc(|js| js.encode_u128(v as u128)));
+    }
+    for &v in [0, -1, 42, -100, 9_876_543_210, -9_876_543_210].iter() {
+        assert_eq!(v.to_string(), enc(|js| js.encode_i64(v)));
+        assert_eq!(v.to_string(), enc(|js| js.encode_i128(v as i128)));
+        assert_eq!((v as isize).to_string(), enc(|js| js.encode_isize(v as isize)));
+    }
+    assert_eq!("340282366920938463463374607431768211455", enc(|js| js.encode_u128(u128::MAX)));
+    assert_eq!(usize::MAX.to_string(), enc(|js| js.encode_usize(usize::MAX)));
+    assert_eq!(isize::MIN.to_string(), enc(|js| js.encode_isize(isize::MIN)));
+
+    assert_eq!("{\"id\":1234567890123,\"v\":[-1,2]}", enc(|js| js.encode_obj(|jso| {
+        jso.encode_field_u64("id", 1_234_567_890_123);
+        jso.encode_field_array("v", |jsa| {
+            jsa.encode_elm_i64(-1);
+            jsa.encode_elm_usize(2);
+        });
+    })));
+}
+
+#[test]
+fn test_encode_int_boundaries() {
+    macro_rules! check {
+        ($t:ident, $method:ident) => {{
+            let mut values: Vec<$t> = vec![$t::MIN, $t::MIN + 1, $t::MAX - 1, $t::MAX, 0, 1];
+            // every power of ten (and its neighbours) that fits
+            let mut p: $t = 1;
+            loop {
+                values.push(p - 1);
+                values.push(p);
+                values.push(p + 1);
+                values.push((0 as $t).wrapping_sub(p));
+                match p.checked_mul(10) {
+                    Some(next) => p = next,
+                    None => break,
+                }
+            }
+            for &v in values.iter() {
+                let mut js = JsonEncoder::new();
+                js.$method(v);
+                assert_eq!(v.to_string().as_bytes(), &js.into_vec()[..]);
+            }
+        }}
+    }
+
+    check!(i32, encode_i32);
+    check!(u32, encode_u32);
+    check!(i64, encode_i64);
+    check!(u64, encode_u64);
+    check!(i128, encode_i128);
+    check!(u128, encode_u128);
+    check!(isize, encode_isize);
+    check!(usize, encode_usize);
+}
+
+#[test]
+fn test_json_bool_null() {
+    let mut js = JsonEncoder::new();
+    js.encode_obj(|jso| {
+        jso.encode_field_bool("t", true);
+        jso.encode_field_null("n");
+        jso.encode_field_array("a", |jsa| {
+            jsa.encode_elm_bool(false);
+            jsa.encode_elm_null();
+        });
     });
+    assert_eq!(&b"{\"t\":true,\"n\":null,\"a\":[false,null]}"[..], &js.into_vec()[..]);
+
+    let mut js = JsonEncoder::new();
+    {
+        let mut obj = js.obj();
+        obj.field("ok").value(true);
+        obj.field("none").null();
+        {
+            let mut v = obj.field("v").vec();
+            v.element_with_value(false);
+            v.element().null();
+            v.end();
+        }
+        obj.end();
+    }
+    assert_eq!(&b"{\"ok\":true,\"none\":null,\"v\":[false,null]}"[..], &js.into_vec()[..]);
 }
 
-#[cfg(test)]
-const STR: &'static str = "A string that we want\nto \"escape\". A string that we want\nto \"escape\". A string that we want\nto \"escape\". ";
+#[test]
+fn test_json_encodable_std_types() {
+    fn enc<T: JsonEncodable>(val: T) -> String {
+        let mut js = JsonEncoder::new();
+        val.encode(&mut js);
+        String::from_utf8(js.into_vec()).unwrap()
+    }
+
+    assert_eq!("\"abc\"", enc(String::from("abc")));
+    assert_eq!("\"abc\"", enc(Cow::Borrowed("abc")));
+    assert_eq!("\"abc\"", enc(Cow::Owned::<str>(String::from("abc"))));
+    assert_eq!("null", enc(None::<i32>));
+    assert_eq!("1", enc(Some(1)));
+    assert_eq!("[]", enc(Vec::<i32>::new()));
+    assert_eq!("[1,2,3]", enc(vec![1, 2, 3]));
+    assert_eq!("[\"a\",\"b\"]", enc(&["a", "b"][..]));
+    assert_eq!("[[1,null],[2,3]]", enc([[Some(1), None], [Some(2), Some(3)]]));
+    assert_eq!("[1,\"x\",true]", enc((1, "x", true)));
+    assert_eq!("null", enc(()));
+    assert_eq!("1.5", enc(Box::new(1.5)));
+    assert_eq!("\"rc\"", enc(Rc::new(String::from("rc"))));
+    assert_eq!("[1]", enc(Arc::new(vec![1u64])));
+    let boxed: Box<str> = "boxed".into();
+    assert_eq!("\"boxed\"", enc(&boxed));
+
+    let mut map = BTreeMap::new();
+    map.insert("b", vec![2]);
+    map.insert("a", vec![1]);
+    assert_eq!("{\"a\":[1],\"b\":[2]}", enc(&map));
+
+    #[cfg(feature = "std")]
+    {
+        let mut map = HashMap::new();
+        map.insert(String::from("k"), Some(false));
+        assert_eq!("{\"k\":false}", enc(map));
+    }
 
-#[bench]
-fn bench_encode_str_noescape(b: &mut test::Bencher) {
-    let mut js = JsonEncoder::with_capacity(400);
-    b.iter(|| {
-        let n = test::black_box(100_0000);
-        for _ in (0..n) {
-            js.clear();
-            js.encode_str_noescape(STR);
+    let mut js = JsonEncoder::new();
+    {
+        let mut obj = js.obj();
+        obj.field("tags").value(vec!["x", "y"]);
+        {
+            let mut v = obj.field("v").vec();
+            v.element_with_value((1, 2));
+            v.end();
         }
+        obj.end();
+    }
+    assert_eq!(&b"{\"tags\":[\"x\",\"y\"],\"v\":[[1,2]]}"[..], &js.into_vec()[..]);
+}
+
+#[test]
+fn test_json_tagged_variants() {
+    let mut js = JsonEncoder::new();
+    js.encode_array(|jsa| {
+        jsa.encode_elm(|js| js.encode_externally_tagged("Circle", |js| js.encode_f64(1.5)));
+        jsa.encode_elm(|js| js.encode_internally_tagged("type", "Rect", |jso| {
+            jso.encode_field_u64("w", 3);
+            jso.encode_field_u64("h", 4);
+        }));
+        jsa.encode_elm(|js| js.encode_adjacently_tagged("t", "c", "Point", |js| (1, 2).encode(js)));
     });
+    assert_eq!(&b"[{\"Circle\":1.5},{\"type\":\"Rect\",\"w\":3,\"h\":4},{\"t\":\"Point\",\"c\":[1,2]}]"[..],
+               &js.into_vec()[..]);
 }
 
-#[bench]
-fn bench_encode_str(b: &mut test::Bencher) {
-    let mut js = JsonEncoder::with_capacity(400);
-    b.iter(|| {
-        let n = test::black_box(100_0000);
-        for _ in (0..n) {
-            js.clear();
-            js.encode_str(STR);
-        }
+#[test]
+fn test_json_pretty() {
+    let mut js = JsonEncoder::new();
+    js.set_pretty(Some(PrettyConfig::default()));
+    js.encode_obj(|jso| {
+        jso.encode_field_u64("a", 1);
+        jso.encode_field_array("b", |jsa| {
+            jsa.encode_elm_bool(true);
+            jsa.encode_elm_obj(|_| ());
+            jsa.encode_elm(|js| js.encode_array(|_| ()));
+        });
+        jso.encode_field_obj("c", |jso| jso.encode_field_null("d"));
     });
+    assert_eq!("{\n  \"a\": 1,\n  \"b\": [\n    true,\n    {},\n    []\n  ],\n  \"c\": {\n    \"d\": null\n  }\n}",
+               String::from_utf8(js.into_vec()).unwrap());
+
+    let mut js = JsonEncoder::new();
+    js.set_pretty(Some(PrettyConfig {
+        indent: Indent::Tabs(1),
+        newline: Newline::CrLf,
+        space_after_colon: false,
+        line_width: None,
+    }));
+    {
+        let mut obj = js.obj();
+        let mut vec = obj.field("x").vec();
+        vec.element().value(1);
+        vec.end();
+        obj.field("y").value("z");
+        obj.end();
+    }
+    js.encode_internally_tagged("type", "T", |jso| jso.encode_field_u64("n", 2));
+    assert_eq!("{\r\n\t\"x\":[\r\n\t\t1\r\n\t],\r\n\t\"y\":\"z\"\r\n}{\r\n\t\"type\":\"T\",\r\n\t\"n\":2\r\n}",
+               String::from_utf8(js.into_vec()).unwrap());
 }
 
-#[bench]
-fn bench_encode_str_(b: &mut test::Bencher) {
-    let mut js = JsonEncoder::with_capacity(400);
-    b.iter(|| {
-        let n = test::black_box(100_0000);
-        for _ in (0..n) {
-            js.clear();
-            js.encode_str_(STR);
-        }
+#[test]
+fn test_json_pretty_line_width() {
+    let mut js = JsonEncoder::new();
+    js.set_pretty(Some(PrettyConfig {line_width: Some(38), ..PrettyConfig::default()}));
+    js.encode_obj(|jso| {
+        jso.encode_field_array("short", |jsa| for i in 0..3 { jsa.encode_elm_u64(i) });
+        jso.encode_field_array("long", |jsa| for i in 0..10 { jsa.encode_elm_u64(i) });
+        jso.encode_field_obj("o", |jso| {
+            jso.encode_field_str("s", ", \"x\": ");
+            jso.encode_field(",", |js| js.encode_raw(b"[1,2]"));
+        });
+        jso.encode_field_array("e", |_| ());
     });
+    assert_eq!("{\n  \"short\": [0, 1, 2],\n  \"long\": [\n    0,\n    1,\n    2,\n    3,\n    4,\n    5,\n    6,\n    7,\n    8,\n    9\n  ],\n  \"o\": {\"s\": \", \\\"x\\\": \", \",\": [1, 2]},\n  \"e\": []\n}",
+               String::from_utf8(js.into_vec()).unwrap());
+
+    let mut js = JsonEncoder::new();
+    js.set_pretty(Some(PrettyConfig {line_width: Some(80), ..PrettyConfig::default()}));
+    let mut vec = JsonVec::open(&mut js);
+    vec.element().value(vec![1, 2]);
+    vec.element().value(());
+    vec.end();
+    assert_eq!("[[1, 2], null]", String::from_utf8(js.into_vec()).unwrap());
 }
 
-#[bench]
-fn bench_encode_str3(b: &mut test::Bencher) {
-    let mut js = JsonEncoder::with_capacity(400);
-    b.iter(|| {
-        let n = test::black_box(100_0000);
-        for _ in (0..n) {
-            js.clear();
-            js.encode_str3(STR);
+#[cfg(all(test, feature = "std"))]
+struct TestWriter {
+    chunks: Rc<::std::cell::RefCell<Vec<Vec<u8>>>>,
+    fail: bool,
+}
+
+#[cfg(all(test, feature = "std"))]
+impl io::Write for TestWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.fail {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"));
         }
-    });
+        self.chunks.borrow_mut().push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
+#[cfg(feature = "std")]
+#[test]
+fn test_json_writer() {
+    let chunks = Rc::new(::std::cell::RefCell::new(Vec::new()));
+    let mut js = JsonEncoder::with_writer(TestWriter {chunks: chunks.clone(), fail: false}, 16);
+    js.encode_obj(|jso| {
+        jso.encode_field_array("a", |jsa| for i in 0..100 { jsa.encode_elm_u64(i) });
+        // nothing is written out while it may still be rolled back
+        let result = jso.try_encode_field("x", |js| js.try_encode_array(|jsa| {
+            for i in 0..100 { jsa.encode_elm_u64(i) }
+            Err::<(), _>(JsonError::Custom("rolled back".to_string()))
+        }));
+        assert!(result.is_err());
+        jso.encode_field_str("b", "end");
+    });
+    js.flush().unwrap();
+
+    let mut expected = JsonEncoder::new();
+    expected.encode_obj(|jso| {
+        jso.encode_field_array("a", |jsa| for i in 0..100 { jsa.encode_elm_u64(i) });
+        jso.encode_field_str("b", "end");
+    });
+    let chunks = chunks.borrow();
+    assert!(chunks.len() > 10);
+    assert!(chunks[..chunks.len() - 1].iter().all(|chunk| chunk.len() < 20));
+    assert_eq!(expected.into_vec(), chunks.concat());
+
+    // objects are written out between fields, like arrays between elements
+    let chunks = Rc::new(::std::cell::RefCell::new(Vec::new()));
+    let mut js = JsonEncoder::with_writer(TestWriter {chunks: chunks.clone(), fail: false}, 16);
+    js.encode_obj(|jso| for i in 0..100 { jso.encode_field_u64(&i.to_string(), i) });
+    js.flush().unwrap();
+
+    let mut expected = JsonEncoder::new();
+    expected.encode_obj(|jso| for i in 0..100 { jso.encode_field_u64(&i.to_string(), i) });
+    let chunks = chunks.borrow();
+    assert!(chunks.len() > 10);
+    assert!(chunks[..chunks.len() - 1].iter().all(|chunk| chunk.len() < 30));
+    assert_eq!(expected.into_vec(), chunks.concat());
+
+    let mut js = JsonEncoder::with_writer(TestWriter {chunks: Rc::default(), fail: true}, 4);
+    js.encode_array(|jsa| for i in 0..10 { jsa.encode_elm_u64(i) });
+    assert_eq!(Some(&JsonError::Io(io::ErrorKind::BrokenPipe)), js.error());
+    // the writer's own error first, then one of the same kind
+    assert_eq!("closed", js.flush().unwrap_err().to_string());
+    assert_eq!(io::ErrorKind::BrokenPipe, js.flush().unwrap_err().kind());
+
+    struct FlushFails;
+    impl io::Write for FlushFails {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
 
-#[bench]
-fn bench_encode_str2(b: &mut test::Bencher) {
-    let mut js = JsonEncoder::with_capacity(400);
-    b.iter(|| {
-        let n = test::black_box(100_0000);
-        for _ in (0..n) {
-            js.clear();
-            js.encode_str2(STR);
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::Error::other("flush failed"))
         }
+    }
+    let mut js = JsonEncoder::with_writer(FlushFails, 4);
+    js.encode_u64(1);
+    assert_eq!("flush failed", js.into_writer().err().unwrap().to_string());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_json_snapshot_streaming() {
+    fn encode<S: Sink>(js: &mut JsonEncoder<S>, rollback: bool) {
+        js.encode_array(|jsa| {
+            jsa.encode_elm_obj(|jso| {
+                for i in 0..50 { jso.encode_field_u64(&i.to_string(), i) }
+                let snapshot = jso.snapshot();
+                if rollback {
+                    for i in 50..100 { jso.encode_field_u64(&i.to_string(), i) }
+                    jso.rollback(snapshot);
+                }
+            });
+            jsa.encode_elm(|js| {
+                let mut vec = JsonVec::open(js);
+                vec.element_with_value(1_i32);
+                let snapshot = vec.snapshot();
+                if rollback {
+                    for i in 0..100 { vec.element_with_value(i) }
+                    vec.rollback(snapshot);
+                }
+                vec.end();
+            });
+            for i in 0..100 { jsa.encode_elm_u64(i) }
+        });
+    }
+
+    let chunks = Rc::new(::std::cell::RefCell::new(Vec::new()));
+    let mut js = JsonEncoder::with_writer(TestWriter {chunks: chunks.clone(), fail: false}, 16);
+    encode(&mut js, true);
+    js.flush().unwrap();
+
+    let mut expected = JsonEncoder::new();
+    encode(&mut expected, false);
+    let chunks = chunks.borrow();
+    // rolling back does not reach into output already written out, and
+    // streaming carries on after the containers with snapshots are closed
+    assert!(chunks.len() > 10);
+    assert!(chunks[..chunks.len() - 1].iter().all(|chunk| chunk.len() < 30));
+    assert_eq!(expected.into_vec(), chunks.concat());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_json_line_width_streaming() {
+    fn encode<S: Sink>(js: &mut JsonEncoder<S>) {
+        js.set_pretty(Some(PrettyConfig {line_width: Some(30), ..PrettyConfig::default()}));
+        js.encode_array(|jsa| jsa.encode_elm_str("0123456789abcdefghijkl"));
+        js.encode_array(|jsa| for i in 0..2 { jsa.encode_elm_u64(i) });
+    }
+    let chunks = Rc::new(::std::cell::RefCell::new(Vec::new()));
+    let mut js = JsonEncoder::with_writer(TestWriter {chunks: chunks.clone(), fail: false}, 16);
+    encode(&mut js);
+    js.flush().unwrap();
+
+    // the second array starts in column 26, behind output already written
+    // out, and does not fit
+    let mut expected = JsonEncoder::new();
+    encode(&mut expected);
+    let expected = expected.into_vec();
+    assert_eq!("[\"0123456789abcdefghijkl\"][\n  0,\n  1\n]", str::from_utf8(&expected).unwrap());
+    assert_eq!(2, chunks.borrow().len());
+    assert_eq!(expected, chunks.borrow().concat());
+}
+
+#[cfg(test)]
+fn encode_sink_sample<S: Sink>(js: &mut JsonEncoder<S>) {
+    js.encode_obj(|jso| {
+        jso.encode_field_str("s", "a\"b\n");
+        jso.encode_field_array("a", |jsa| for i in 0..3 { jsa.encode_elm_i64(-i) });
     });
 }
 
 #[test]
-fn test_encode_u32() {
+fn test_json_sinks() {
+    let expected = &b"{\"s\":\"a\\\"b\\n\",\"a\":[0,-1,-2]}"[..];
+
+    let mut buf = [0u8; 64];
+    let mut js = JsonEncoder::with_sink(SliceSink::new(&mut buf));
+    encode_sink_sample(&mut js);
+    assert_eq!(expected, js.finish().unwrap().written());
+
+    // an exact fit works, although it leaves no room for worst-case escaping
+    let mut buf = vec![0u8; expected.len()];
+    let mut js = JsonEncoder::with_sink(SliceSink::new(&mut buf));
+    encode_sink_sample(&mut js);
+    assert_eq!(expected, js.finish().unwrap().written());
+
+    let mut js = JsonEncoder::with_sink(ArraySink::<64>::new());
+    encode_sink_sample(&mut js);
+    assert_eq!(expected, js.finish().unwrap().written());
+
+    let mut buf = vec![0u8; expected.len() - 1];
+    let mut js = JsonEncoder::with_sink(SliceSink::new(&mut buf));
+    encode_sink_sample(&mut js);
+    assert_eq!(Some(&JsonError::SinkFull), js.error());
+    assert_eq!(Err(JsonError::SinkFull), js.finish().map(|_| ()));
+
+    let mut out = String::from(">");
+    {
+        let mut js = JsonEncoder::with_sink(FmtSink::new(&mut out));
+        encode_sink_sample(&mut js);
+        js.finish().unwrap();
+    }
+    assert_eq!(expected, &out.as_bytes()[1..]);
+
+    let mut vec = b">".to_vec();
+    {
+        let mut js = JsonEncoder::with_sink(&mut vec);
+        encode_sink_sample(&mut js);
+        js.finish().unwrap();
+    }
+    assert_eq!(expected, &vec[1..]);
+}
+
+#[cfg(feature = "bytes")]
+#[test]
+fn test_json_bytes_sink() {
+    let mut js = JsonEncoder::with_sink(bytes::BytesMut::new());
+    encode_sink_sample(&mut js);
+    assert_eq!(&b"{\"s\":\"a\\\"b\\n\",\"a\":[0,-1,-2]}"[..], &js.finish().unwrap()[..]);
+}
+
+#[test]
+fn test_json_limits() {
+    fn enc(limits: Limits) -> Result<Vec<u8>, JsonError> {
+        let mut js = JsonEncoder::new();
+        js.set_limits(limits);
+        encode_sink_sample(&mut js);
+        js.finish()
+    }
+    let expected = &b"{\"s\":\"a\\\"b\\n\",\"a\":[0,-1,-2]}"[..];
+
+    let exact = Limits {max_depth: Some(2), max_output_len: Some(expected.len()), max_str_len: Some(4)};
+    assert_eq!(expected, &enc(exact).unwrap()[..]);
+
+    assert_eq!(Err(JsonError::DepthLimitExceeded), enc(Limits {max_depth: Some(1), ..exact}));
+    assert_eq!(Err(JsonError::OutputLimitExceeded), enc(Limits {max_output_len: Some(expected.len() - 1), ..exact}));
+    assert_eq!(Err(JsonError::StrTooLong), enc(Limits {max_str_len: Some(3), ..exact}));
+
+    // no output follows the first error
     let mut js = JsonEncoder::new();
-    js.encode_u32(0);
-    let vec = js.into_vec();
-    assert_eq!(b"0", &vec[..]);
+    js.set_limits(Limits {max_depth: Some(1), ..Limits::default()});
+    js.encode_array(|jsa| {
+        jsa.encode_elm_i64(1);
+        jsa.encode_elm(|js| js.encode_array(|jsa| jsa.encode_elm_i64(2)));
+        jsa.encode_elm_i64(3);
+    });
+    assert_eq!(Some(&JsonError::DepthLimitExceeded), js.error());
+    assert_eq!(&b"[1,"[..], &js.buffer.sink[..]);
+
+    // clear() starts over under the same limits
+    js.clear();
+    js.encode_array(|jsa| jsa.encode_elm_i64(1));
+    assert_eq!(&b"[1]"[..], &js.finish().unwrap()[..]);
+}
+
+#[test]
+fn test_json_try_closures() {
+    #[derive(Debug, PartialEq)]
+    enum Error {
+        Json(JsonError),
+        Invalid(i64),
+    }
+
+    impl From<JsonError> for Error {
+        fn from(err: JsonError) -> Error {
+            Error::Json(err)
+        }
+    }
+
+    fn encode_valid(jsa: &mut JsonArrayEncoder, values: &[i64]) -> Result<(), Error> {
+        for &v in values {
+            jsa.try_encode_elm(|js| {
+                if v < 0 {
+                    return Err(Error::Invalid(v));
+                }
+                js.encode_i64(v);
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
 
     let mut js = JsonEncoder::new();
-    js.encode_u32(123000);
-    let vec = js.into_vec();
-    assert_eq!(b"123000", &vec[..]);
+    js.encode_obj(|jso| {
+        assert_eq!(Ok(2), jso.try_encode_field("a", |js| js.try_encode_array(|jsa| encode_valid(jsa, &[1, 2]).map(|_| 2))));
+        assert_eq!(Err(Error::Invalid(-1)), jso.try_encode_field("b", |js| js.try_encode_array(|jsa| encode_valid(jsa, &[3, -1]))));
+        jso.encode_field_array("c", |jsa| {
+            assert_eq!(Err(Error::Invalid(-2)), encode_valid(jsa, &[4, -2, 5]));
+        });
+        assert_eq!(Err(Error::Invalid(-3)), jso.try_encode_field("d", |js| js.try_encode_obj(|jso| {
+            jso.encode_field_i64("x", 6);
+            Err::<(), _>(Error::Invalid(-3))
+        })));
+    });
+    assert_eq!("{\"a\":[1,2],\"c\":[4]}", String::from_utf8(js.finish().unwrap()).unwrap());
 
+    // the same in pretty mode, where the layout state is rolled back, too
     let mut js = JsonEncoder::new();
-    js.encode_u32(1230001);
-    let vec = js.into_vec();
-    assert_eq!(b"1230001", &vec[..]);
+    js.set_pretty(Some(PrettyConfig {line_width: Some(80), ..PrettyConfig::default()}));
+    let result: Result<(), Error> = js.try_encode_array(|jsa| {
+        jsa.encode_elm_i64(1);
+        let _ = jsa.try_encode_elm(|js| js.try_encode_obj(|_| Err::<(), _>(Error::Invalid(0))));
+        encode_valid(jsa, &[2])
+    });
+    assert_eq!(Ok(()), result);
+    assert_eq!("[1, 2]", String::from_utf8(js.finish().unwrap()).unwrap());
+
+    // limits are reported as errors, without calling the closure beyond the depth limit
+    let mut js = JsonEncoder::new();
+    js.set_limits(Limits {max_depth: Some(1), ..Limits::default()});
+    let mut calls = 0;
+    let result: Result<(), Error> = js.try_encode_array(|jsa| jsa.try_encode_elm(|js| js.try_encode_array(|_| {
+        calls += 1;
+        Ok(())
+    })));
+    assert_eq!(Err(Error::Json(JsonError::DepthLimitExceeded)), result);
+    assert_eq!(0, calls);
+    // the error was rolled back along with the output
+    assert_eq!(None, js.error());
+    assert_eq!(b"", &js.finish().unwrap()[..]);
+
+    // encoding carries on after a rolled back error or exceeded limit
+    let mut js = JsonEncoder::new();
+    js.set_non_finite(NonFinite::Error);
+    js.set_limits(Limits {max_output_len: Some(24), ..Limits::default()});
+    js.encode_obj(|jso| {
+        let result: Result<(), Error> = jso.try_encode_field("nan", |js| {
+            js.encode_f64(f64::NAN);
+            Ok(())
+        });
+        assert_eq!(Err(Error::Json(JsonError::NonFiniteFloat)), result);
+        let result: Result<(), Error> = jso.try_encode_field("long", |js| {
+            js.encode_str("a string that does not fit");
+            Ok(())
+        });
+        assert_eq!(Err(Error::Json(JsonError::OutputLimitExceeded)), result);
+        jso.encode_field_f64("x", 1.5);
+    });
+    assert_eq!(None, js.error());
+    assert_eq!("{\"x\":1.5}", String::from_utf8(js.finish().unwrap()).unwrap());
+
+    // errors from before the checkpoint are kept
+    let mut js = JsonEncoder::new();
+    js.set_non_finite(NonFinite::Error);
+    js.encode_array(|jsa| {
+        jsa.encode_elm_f64(f64::INFINITY);
+        let result: Result<(), JsonError> = jsa.try_encode_elm(|js| {
+            js.encode_u64(1);
+            Ok(())
+        });
+        assert_eq!(Err(JsonError::NonFiniteFloat), result);
+    });
+    assert_eq!(Err(JsonError::NonFiniteFloat), js.finish());
+}
+
+#[cfg(test)]
+fn escape_with(f: unsafe fn(*mut u8, &[u8]) -> usize, bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(6 * bytes.len());
+    unsafe {
+        let len = f(out.as_mut_ptr(), bytes);
+        out.set_len(len);
+    }
+    out
+}
+
+/// Inputs for comparing the escaping implementations: every special byte
+/// at every position of short strings, and pseudo-random longer ones.
+#[cfg(test)]
+fn escape_test_inputs() -> Vec<Vec<u8>> {
+    const SPECIAL: &'static [u8] = b"\"\\\x00\x08\n\x1f \x7f\x80\xff";
+    let mut inputs = Vec::new();
+    for len in 0..80 {
+        let base: Vec<u8> = (0..len).map(|i| b'a' + (i % 26) as u8).collect();
+        inputs.push(base.clone());
+        for pos in 0..len {
+            for &special in SPECIAL {
+                let mut input = base.clone();
+                input[pos] = special;
+                inputs.push(input);
+            }
+        }
+    }
+    let mut seed: u32 = 1;
+    for _ in 0..1000 {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        let len = (seed >> 16) as usize % 300;
+        let input = (0..len).map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let r = (seed >> 16) as usize;
+            match r % 20 {
+                0 => SPECIAL[r / 20 % SPECIAL.len()],
+                _ => (r % 256) as u8,
+            }
+        }).collect();
+        inputs.push(input);
+    }
+    inputs
+}
+
+#[test]
+fn test_escape_swar() {
+    for input in escape_test_inputs() {
+        assert_eq!(escape_with(escape_into_scalar, &input), escape_with(escape_into_swar, &input), "{:?}", input);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_escape_simd() {
+    let mut impls: Vec<unsafe fn(*mut u8, &[u8]) -> usize> = vec![simd::escape_into_sse2, simd::escape_into];
+    if is_x86_feature_detected!("avx2") {
+        impls.push(simd::escape_into_avx2);
+    }
+    for input in escape_test_inputs() {
+        let expected = escape_with(escape_into_scalar, &input);
+        for &f in &impls {
+            assert_eq!(expected, escape_with(f, &input), "{:?}", input);
+        }
+    }
+}
+
+#[test]
+fn test_json_non_ascii() {
+    fn enc(policy: NonAscii, s: &str) -> String {
+        let mut js = JsonEncoder::new();
+        js.set_non_ascii(policy);
+        js.encode_obj(|jso| jso.encode_field(s, |js| js.encode_str(s)));
+        String::from_utf8(js.into_vec()).unwrap()
+    }
+
+    let s = "a\u{e9}\u{2028}\u{1f}\"\u{1f600}z";
+    assert_eq!(format!("{{\"{0}\":\"{0}\"}}", "a\u{e9}\u{2028}\\u001f\\\"\u{1f600}z"), enc(NonAscii::Utf8, s));
+    assert_eq!(format!("{{\"{0}\":\"{0}\"}}", "a\\u00e9\\u2028\\u001f\\\"\\ud83d\\ude00z"), enc(NonAscii::EscapeLower, s));
+    assert_eq!(format!("{{\"{0}\":\"{0}\"}}", "a\\u00E9\\u2028\\u001F\\\"\\uD83D\\uDE00z"), enc(NonAscii::EscapeUpper, s));
+    assert_eq!("{\"\\uffff\\ud800\\udc00\\udbff\\udfff\":\"\\uffff\\ud800\\udc00\\udbff\\udfff\"}",
+               enc(NonAscii::EscapeLower, "\u{ffff}\u{10000}\u{10ffff}"));
+
+    // without room for the worst case, characters are escaped one by one
+    let expected = &b"\"\\u00E9\\uD83D\\uDE00!\""[..];
+    let mut buf = vec![0u8; expected.len()];
+    let mut js = JsonEncoder::with_sink(SliceSink::new(&mut buf));
+    js.set_non_ascii(NonAscii::EscapeUpper);
+    js.encode_str("\u{e9}\u{1f600}!");
+    assert_eq!(expected, js.finish().unwrap().written());
+
+    // names and strings escaped beforehand are covered, too
+    const KEY: JsonKey = json_key!("k\u{e9}\"");
+    for &pretty in &[None, Some(PrettyConfig::default())] {
+        let mut js = JsonEncoder::new();
+        js.set_non_ascii(NonAscii::EscapeLower);
+        js.set_pretty(pretty);
+        js.encode_obj(|jso| {
+            jso.encode_key(KEY, |js| js.encode_str_preescaped("\"\u{e9}\"".as_bytes()));
+            jso.encode_key(json_key!("t\u{e9}"), |js| js.encode_str_preescaped("\"\u{1f600}\"".as_bytes()));
+            jso.encode_field_raw("r\u{e9}", |js| js.encode_str_noescape("\u{e9}"));
+        });
+        let out = String::from_utf8(js.into_vec()).unwrap();
+        let compact: String = out.chars().filter(|c| !c.is_whitespace()).collect();
+        assert_eq!("{\"k\\u00e9\\\"\":\"\\u00e9\",\"t\\u00e9\":\"\\ud83d\\ude00\",\"r\\u00e9\":\"\\u00e9\"}", compact);
+    }
 }
@@ -0,0 +1,597 @@
+//! A `serde::Serializer` writing through `JsonEncoder`.
+//!
+//! The output matches `serde_json`'s compact output:
+//!
+//! ```
+//! # extern crate json_encoder;
+//! # extern crate serde;
+//! use serde::Serialize;
+//!
+//! # fn main() {
+//! let mut js = json_encoder::JsonEncoder::new();
+//! (1, "two", [3.5]).serialize(&mut js).unwrap();
+//! assert_eq!(&b"[1,\"two\",[3.5]]"[..], &js.finish().unwrap()[..]);
+//! # }
+//! ```
+
+use serde::ser::{self, Impossible, Serialize};
+use core::fmt::Display;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+use {JsonArrayEncoder, JsonEncoder, JsonError, JsonObjectEncoder, Sink};
+
+impl ser::Error for JsonError {
+    fn custom<T: Display>(msg: T) -> JsonError {
+        JsonError::Custom(msg.to_string())
+    }
+}
+
+/// Writes a float formatted by ryu the way `serde_json` does, which puts a
+/// sign on positive exponents, as in `1e+300`.
+#[inline]
+fn encode_float<S: Sink>(js: &mut JsonEncoder<S>, digits: &str) {
+    let digits = digits.as_bytes();
+    match digits.iter().position(|&b| b == b'e') {
+        Some(e) if digits[e + 1] != b'-' => {
+            js.encode_raw(&digits[..e + 1]);
+            js.encode_raw(b"+");
+            js.encode_raw(&digits[e + 1..]);
+        }
+        _ => js.encode_raw(digits),
+    }
+}
+
+/// Serializes `value` into a new vector.
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, JsonError> {
+    let mut js = JsonEncoder::with_capacity(128);
+    value.serialize(&mut js)?;
+    js.finish()
+}
+
+impl<'a, S: Sink> ser::Serializer for &'a mut JsonEncoder<S> {
+    type Ok = ();
+    type Error = JsonError;
+
+    type SerializeSeq = SerializeArray<'a, S>;
+    type SerializeTuple = SerializeArray<'a, S>;
+    type SerializeTupleStruct = SerializeArray<'a, S>;
+    type SerializeTupleVariant = SerializeArray<'a, S>;
+    type SerializeMap = SerializeObject<'a, S>;
+    type SerializeStruct = SerializeObject<'a, S>;
+    type SerializeStructVariant = SerializeObject<'a, S>;
+
+    #[inline]
+    fn serialize_bool(self, v: bool) -> Result<(), JsonError> {
+        self.encode_bool(v);
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_i8(self, v: i8) -> Result<(), JsonError> {
+        self.encode_i32(v as i32);
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_i16(self, v: i16) -> Result<(), JsonError> {
+        self.encode_i32(v as i32);
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_i32(self, v: i32) -> Result<(), JsonError> {
+        self.encode_i32(v);
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_i64(self, v: i64) -> Result<(), JsonError> {
+        self.encode_i64(v);
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_i128(self, v: i128) -> Result<(), JsonError> {
+        self.encode_i128(v);
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_u8(self, v: u8) -> Result<(), JsonError> {
+        self.encode_u32(v as u32);
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_u16(self, v: u16) -> Result<(), JsonError> {
+        self.encode_u32(v as u32);
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_u32(self, v: u32) -> Result<(), JsonError> {
+        self.encode_u32(v);
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_u64(self, v: u64) -> Result<(), JsonError> {
+        self.encode_u64(v);
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_u128(self, v: u128) -> Result<(), JsonError> {
+        self.encode_u128(v);
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_f32(self, v: f32) -> Result<(), JsonError> {
+        if !v.is_finite() {
+            return self.try_encode_f32(v);
+        }
+        encode_float(self, ::ryu::Buffer::new().format_finite(v));
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_f64(self, v: f64) -> Result<(), JsonError> {
+        if !v.is_finite() {
+            return self.try_encode_f64(v);
+        }
+        encode_float(self, ::ryu::Buffer::new().format_finite(v));
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_char(self, v: char) -> Result<(), JsonError> {
+        self.encode_str(v.encode_utf8(&mut [0; 4]));
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_str(self, v: &str) -> Result<(), JsonError> {
+        self.encode_str(v);
+        self.check_error()
+    }
+
+    #[inline]
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), JsonError> {
+        self.encode_array(|jsa| {
+            for &byte in v.iter() {
+                jsa.encode_elm(|js| js.encode_u32(byte as u32));
+            }
+        });
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_none(self) -> Result<(), JsonError> {
+        self.encode_null();
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), JsonError> {
+        value.serialize(self)
+    }
+
+    #[inline]
+    fn serialize_unit(self) -> Result<(), JsonError> {
+        self.encode_null();
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), JsonError> {
+        self.encode_null();
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<(), JsonError> {
+        self.encode_str(variant);
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<(), JsonError> {
+        value.serialize(self)
+    }
+
+    #[inline]
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32, variant: &'static str, value: &T) -> Result<(), JsonError> {
+        self.encode_externally_tagged(variant, |js| value.serialize(js))
+    }
+
+    #[inline]
+    fn serialize_seq(self, _len: Option<usize>) -> Result<SerializeArray<'a, S>, JsonError> {
+        SerializeArray::open(self, false)
+    }
+
+    #[inline]
+    fn serialize_tuple(self, _len: usize) -> Result<SerializeArray<'a, S>, JsonError> {
+        SerializeArray::open(self, false)
+    }
+
+    #[inline]
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<SerializeArray<'a, S>, JsonError> {
+        SerializeArray::open(self, false)
+    }
+
+    #[inline]
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, variant: &'static str, _len: usize) -> Result<SerializeArray<'a, S>, JsonError> {
+        open_variant(self, variant);
+        SerializeArray::open(self, true)
+    }
+
+    #[inline]
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeObject<'a, S>, JsonError> {
+        SerializeObject::open(self, false)
+    }
+
+    #[inline]
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<SerializeObject<'a, S>, JsonError> {
+        SerializeObject::open(self, false)
+    }
+
+    #[inline]
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, variant: &'static str, _len: usize) -> Result<SerializeObject<'a, S>, JsonError> {
+        open_variant(self, variant);
+        SerializeObject::open(self, true)
+    }
+}
+
+/// Writes `{"<variant>":`, which `end()` closes with the extra `}`.
+#[inline]
+fn open_variant<S: Sink>(js: &mut JsonEncoder<S>, variant: &str) {
+    js.begin_container(b'{');
+    JsonObjectEncoder {js: js, needs_sep: false}.encode_field(variant, |_| ());
+}
+
+pub struct SerializeArray<'a, S: 'a> {
+    jsa: JsonArrayEncoder<'a, S>,
+    in_variant: bool,
+}
+
+impl<'a, S: Sink> SerializeArray<'a, S> {
+    #[inline]
+    fn open(js: &'a mut JsonEncoder<S>, in_variant: bool) -> Result<SerializeArray<'a, S>, JsonError> {
+        js.begin_container(b'[');
+        // stops the recursion once a limit is exceeded
+        js.check_error::<JsonError>()?;
+        Ok(SerializeArray {jsa: JsonArrayEncoder {js: js, needs_sep: false}, in_variant: in_variant})
+    }
+
+    #[inline]
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JsonError> {
+        self.jsa.encode_elm(|js| value.serialize(js))
+    }
+
+    #[inline]
+    fn close(self) -> Result<(), JsonError> {
+        self.jsa.js.end_container(b']', self.jsa.needs_sep);
+        if self.in_variant {
+            self.jsa.js.end_container(b'}', true);
+        }
+        self.jsa.js.check_error()
+    }
+}
+
+impl<'a, S: Sink> ser::SerializeSeq for SerializeArray<'a, S> {
+    type Ok = ();
+    type Error = JsonError;
+
+    #[inline]
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JsonError> {
+        self.element(value)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), JsonError> {
+        self.close()
+    }
+}
+
+impl<'a, S: Sink> ser::SerializeTuple for SerializeArray<'a, S> {
+    type Ok = ();
+    type Error = JsonError;
+
+    #[inline]
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JsonError> {
+        self.element(value)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), JsonError> {
+        self.close()
+    }
+}
+
+impl<'a, S: Sink> ser::SerializeTupleStruct for SerializeArray<'a, S> {
+    type Ok = ();
+    type Error = JsonError;
+
+    #[inline]
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JsonError> {
+        self.element(value)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), JsonError> {
+        self.close()
+    }
+}
+
+impl<'a, S: Sink> ser::SerializeTupleVariant for SerializeArray<'a, S> {
+    type Ok = ();
+    type Error = JsonError;
+
+    #[inline]
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JsonError> {
+        self.element(value)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), JsonError> {
+        self.close()
+    }
+}
+
+pub struct SerializeObject<'a, S: 'a> {
+    jso: JsonObjectEncoder<'a, S>,
+    in_variant: bool,
+}
+
+impl<'a, S: Sink> SerializeObject<'a, S> {
+    #[inline]
+    fn open(js: &'a mut JsonEncoder<S>, in_variant: bool) -> Result<SerializeObject<'a, S>, JsonError> {
+        js.begin_container(b'{');
+        js.check_error::<JsonError>()?;
+        Ok(SerializeObject {jso: JsonObjectEncoder {js: js, needs_sep: false}, in_variant: in_variant})
+    }
+
+    #[inline]
+    fn field<T: Serialize + ?Sized>(&mut self, key: &str, value: &T) -> Result<(), JsonError> {
+        self.jso.encode_field(key, |js| value.serialize(js))
+    }
+
+    #[inline]
+    fn close(self) -> Result<(), JsonError> {
+        self.jso.js.end_container(b'}', self.jso.needs_sep);
+        if self.in_variant {
+            self.jso.js.end_container(b'}', true);
+        }
+        self.jso.js.check_error()
+    }
+}
+
+impl<'a, S: Sink> ser::SerializeMap for SerializeObject<'a, S> {
+    type Ok = ();
+    type Error = JsonError;
+
+    #[inline]
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), JsonError> {
+        self.jso.js.begin_element(!self.jso.needs_sep);
+        self.jso.needs_sep = true;
+        key.serialize(MapKeySerializer {js: self.jso.js})?;
+        self.jso.js.write_colon();
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JsonError> {
+        value.serialize(&mut *self.jso.js)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), JsonError> {
+        self.close()
+    }
+}
+
+impl<'a, S: Sink> ser::SerializeStruct for SerializeObject<'a, S> {
+    type Ok = ();
+    type Error = JsonError;
+
+    #[inline]
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), JsonError> {
+        self.field(key, value)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), JsonError> {
+        self.close()
+    }
+}
+
+impl<'a, S: Sink> ser::SerializeStructVariant for SerializeObject<'a, S> {
+    type Ok = ();
+    type Error = JsonError;
+
+    #[inline]
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), JsonError> {
+        self.field(key, value)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), JsonError> {
+        self.close()
+    }
+}
+
+/// Writes map keys, which JSON requires to be strings. Like `serde_json`,
+/// numbers, booleans and chars are quoted; everything else is rejected.
+struct MapKeySerializer<'a, S: 'a> {
+    js: &'a mut JsonEncoder<S>,
+}
+
+impl<'a, S: Sink> MapKeySerializer<'a, S> {
+    #[inline]
+    fn quoted<F>(self, f: F) -> Result<(), JsonError> where F: FnOnce(&mut JsonEncoder<S>) {
+        self.js.buffer.push(b'"');
+        f(self.js);
+        self.js.buffer.push(b'"');
+        Ok(())
+    }
+}
+
+impl<'a, S: Sink> ser::Serializer for MapKeySerializer<'a, S> {
+    type Ok = ();
+    type Error = JsonError;
+
+    type SerializeSeq = Impossible<(), JsonError>;
+    type SerializeTuple = Impossible<(), JsonError>;
+    type SerializeTupleStruct = Impossible<(), JsonError>;
+    type SerializeTupleVariant = Impossible<(), JsonError>;
+    type SerializeMap = Impossible<(), JsonError>;
+    type SerializeStruct = Impossible<(), JsonError>;
+    type SerializeStructVariant = Impossible<(), JsonError>;
+
+    #[inline]
+    fn serialize_str(self, v: &str) -> Result<(), JsonError> {
+        self.js.encode_str(v);
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_char(self, v: char) -> Result<(), JsonError> {
+        self.js.encode_str(v.encode_utf8(&mut [0; 4]));
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_bool(self, v: bool) -> Result<(), JsonError> {
+        self.quoted(|js| js.encode_bool(v))
+    }
+
+    #[inline]
+    fn serialize_i8(self, v: i8) -> Result<(), JsonError> {
+        self.quoted(|js| js.encode_i32(v as i32))
+    }
+
+    #[inline]
+    fn serialize_i16(self, v: i16) -> Result<(), JsonError> {
+        self.quoted(|js| js.encode_i32(v as i32))
+    }
+
+    #[inline]
+    fn serialize_i32(self, v: i32) -> Result<(), JsonError> {
+        self.quoted(|js| js.encode_i32(v))
+    }
+
+    #[inline]
+    fn serialize_i64(self, v: i64) -> Result<(), JsonError> {
+        self.quoted(|js| js.encode_i64(v))
+    }
+
+    #[inline]
+    fn serialize_i128(self, v: i128) -> Result<(), JsonError> {
+        self.quoted(|js| js.encode_i128(v))
+    }
+
+    #[inline]
+    fn serialize_u8(self, v: u8) -> Result<(), JsonError> {
+        self.quoted(|js| js.encode_u32(v as u32))
+    }
+
+    #[inline]
+    fn serialize_u16(self, v: u16) -> Result<(), JsonError> {
+        self.quoted(|js| js.encode_u32(v as u32))
+    }
+
+    #[inline]
+    fn serialize_u32(self, v: u32) -> Result<(), JsonError> {
+        self.quoted(|js| js.encode_u32(v))
+    }
+
+    #[inline]
+    fn serialize_u64(self, v: u64) -> Result<(), JsonError> {
+        self.quoted(|js| js.encode_u64(v))
+    }
+
+    #[inline]
+    fn serialize_u128(self, v: u128) -> Result<(), JsonError> {
+        self.quoted(|js| js.encode_u128(v))
+    }
+
+    #[inline]
+    fn serialize_f32(self, v: f32) -> Result<(), JsonError> {
+        if !v.is_finite() {
+            return Err(JsonError::NonFiniteFloat);
+        }
+        self.quoted(|js| encode_float(js, ::ryu::Buffer::new().format_finite(v)))
+    }
+
+    #[inline]
+    fn serialize_f64(self, v: f64) -> Result<(), JsonError> {
+        if !v.is_finite() {
+            return Err(JsonError::NonFiniteFloat);
+        }
+        self.quoted(|js| encode_float(js, ::ryu::Buffer::new().format_finite(v)))
+    }
+
+    #[inline]
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<(), JsonError> {
+        self.serialize_str(variant)
+    }
+
+    #[inline]
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<(), JsonError> {
+        value.serialize(self)
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<(), JsonError> {
+        Err(JsonError::KeyMustBeAString)
+    }
+
+    fn serialize_none(self) -> Result<(), JsonError> {
+        Err(JsonError::KeyMustBeAString)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, _value: &T) -> Result<(), JsonError> {
+        Err(JsonError::KeyMustBeAString)
+    }
+
+    fn serialize_unit(self) -> Result<(), JsonError> {
+        Err(JsonError::KeyMustBeAString)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), JsonError> {
+        Err(JsonError::KeyMustBeAString)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32, _variant: &'static str, _value: &T) -> Result<(), JsonError> {
+        Err(JsonError::KeyMustBeAString)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Impossible<(), JsonError>, JsonError> {
+        Err(JsonError::KeyMustBeAString)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Impossible<(), JsonError>, JsonError> {
+        Err(JsonError::KeyMustBeAString)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Impossible<(), JsonError>, JsonError> {
+        Err(JsonError::KeyMustBeAString)
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, _variant: &'static str, _len: usize) -> Result<Impossible<(), JsonError>, JsonError> {
+        Err(JsonError::KeyMustBeAString)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Impossible<(), JsonError>, JsonError> {
+        Err(JsonError::KeyMustBeAString)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Impossible<(), JsonError>, JsonError> {
+        Err(JsonError::KeyMustBeAString)
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, _variant: &'static str, _len: usize) -> Result<Impossible<(), JsonError>, JsonError> {
+        Err(JsonError::KeyMustBeAString)
+    }
+}
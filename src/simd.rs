@@ -0,0 +1,98 @@
+//! Vectorized string escaping for x86_64.
+//!
+//! The scanners check 16 (SSE2) or 32 (AVX2) bytes at a time for `"`, `\`
+//! and control characters. Each block is stored to the output whole, and
+//! the output position advanced past the clean bytes only, so that an
+//! escape sequence simply overwrites the rest of the block. Like the scalar
+//! loop, they rely on room for `6 * bytes.len()` bytes at `dst`.
+
+use core::arch::x86_64::*;
+
+use super::{escape_byte, escape_into_swar};
+
+/// Strings shorter than this are left to `escape_into_swar`.
+pub const MIN_LEN: usize = 16;
+
+#[inline]
+pub unsafe fn escape_into(dst: *mut u8, bytes: &[u8]) -> usize {
+    if has_avx2() {
+        escape_into_avx2(dst, bytes)
+    } else {
+        escape_into_sse2(dst, bytes)
+    }
+}
+
+#[cfg(feature = "std")]
+#[inline]
+fn has_avx2() -> bool {
+    is_x86_feature_detected!("avx2")
+}
+
+#[cfg(not(feature = "std"))]
+#[inline]
+fn has_avx2() -> bool {
+    cfg!(target_feature = "avx2")
+}
+
+#[target_feature(enable = "sse2")]
+pub unsafe fn escape_into_sse2(dst: *mut u8, bytes: &[u8]) -> usize {
+    let src = bytes.as_ptr();
+    let quote = _mm_set1_epi8(b'"' as i8);
+    let backslash = _mm_set1_epi8(b'\\' as i8);
+    let max_control = _mm_set1_epi8(0x1f);
+
+    let mut i = 0;
+    let mut count = 0;
+    while i + 16 <= bytes.len() {
+        let v = _mm_loadu_si128(src.offset(i as isize) as *const __m128i);
+        _mm_storeu_si128(dst.offset(count as isize) as *mut __m128i, v);
+
+        // there is no unsigned compare, but `max(v, 0x1f) == 0x1f` is `v <= 0x1f`
+        let control = _mm_cmpeq_epi8(_mm_max_epu8(v, max_control), max_control);
+        let special = _mm_or_si128(_mm_or_si128(_mm_cmpeq_epi8(v, quote), _mm_cmpeq_epi8(v, backslash)), control);
+        let mask = _mm_movemask_epi8(special) as u32;
+        if mask == 0 {
+            i += 16;
+            count += 16;
+            continue;
+        }
+
+        let clean = mask.trailing_zeros() as usize;
+        i += clean;
+        count += clean;
+        count += escape_byte(dst.offset(count as isize), bytes, i);
+        i += 1;
+    }
+    count + escape_into_swar(dst.offset(count as isize), &bytes[i..])
+}
+
+#[target_feature(enable = "avx2")]
+pub unsafe fn escape_into_avx2(dst: *mut u8, bytes: &[u8]) -> usize {
+    let src = bytes.as_ptr();
+    let quote = _mm256_set1_epi8(b'"' as i8);
+    let backslash = _mm256_set1_epi8(b'\\' as i8);
+    let max_control = _mm256_set1_epi8(0x1f);
+
+    let mut i = 0;
+    let mut count = 0;
+    while i + 32 <= bytes.len() {
+        let v = _mm256_loadu_si256(src.offset(i as isize) as *const __m256i);
+        _mm256_storeu_si256(dst.offset(count as isize) as *mut __m256i, v);
+
+        let control = _mm256_cmpeq_epi8(_mm256_max_epu8(v, max_control), max_control);
+        let special = _mm256_or_si256(_mm256_or_si256(_mm256_cmpeq_epi8(v, quote), _mm256_cmpeq_epi8(v, backslash)), control);
+        let mask = _mm256_movemask_epi8(special) as u32;
+        if mask == 0 {
+            i += 32;
+            count += 32;
+            continue;
+        }
+
+        let clean = mask.trailing_zeros() as usize;
+        i += clean;
+        count += clean;
+        count += escape_byte(dst.offset(count as isize), bytes, i);
+        i += 1;
+    }
+    count + escape_into_sse2(dst.offset(count as isize), &bytes[i..])
+}
@@ -0,0 +1,523 @@
+//! Output targets of `JsonEncoder`.
+//!
+//! The encoder writes straight into the memory of a `Sink`, reserving room
+//! up front and filling it through raw pointers, just like it does with the
+//! default `Vec<u8>`. `JsonEncoder<S>` is generic over its sink, so the
+//! string and integer encoders are compiled separately for each of them.
+//!
+//! Besides `Vec<u8>`, there are sinks for a preallocated `&mut [u8]`
+//! (`SliceSink`), a fixed `[u8; N]` (`ArraySink`), any `io::Write`
+//! (`IoSink`, with the `std` feature) and any `fmt::Write` (`FmtSink`), and,
+//! with the `bytes` feature, for `bytes::BytesMut`. Other buffers, e.g. ones
+//! allocated from an arena, can implement `Sink` themselves; the trait is
+//! `unsafe` to implement, since the encoder writes to the room a sink
+//! reports without further checks.
+//!
+//! `SliceSink` and `ArraySink` never allocate.
+
+use core::{fmt, str};
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use std::io;
+
+use JsonError;
+
+#[cfg(feature = "std")]
+use JsonEncoder;
+
+/// A buffer the encoder writes into.
+///
+/// # Safety
+///
+/// The encoder writes through raw pointers and relies on the following:
+///
+/// * after `reserve(n)` returned `true`, `spare_ptr()` is valid for writes
+///   of `n` bytes, until the sink is changed in any other way than through
+///   `set_len`;
+/// * `spare_ptr()` points right behind the bytes returned by
+///   `as_mut_slice()`, within the same allocation;
+/// * `set_len(len)`, called with a `len` within that room, makes the first
+///   `len` bytes the ones held, and `len()` returns it afterwards.
+pub unsafe trait Sink {
+    /// Number of bytes held.
+    fn len(&self) -> usize;
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The bytes held.
+    fn as_mut_slice(&mut self) -> &mut [u8];
+
+    /// Keeps only the first `len` bytes held.
+    fn truncate(&mut self, len: usize);
+
+    /// Makes room for `additional` more bytes, without touching the bytes
+    /// held. Returns `false` if the sink cannot grow that far.
+    fn reserve(&mut self, additional: usize) -> bool;
+
+    /// Points to the room made by `reserve`, right behind the bytes held,
+    /// see the safety requirements of the trait.
+    fn spare_ptr(&mut self) -> *mut u8;
+
+    /// Sets the number of bytes held, after writing them to `spare_ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `len` must not exceed the bytes held plus the room made by the last
+    /// `reserve`, and all bytes up to `len` must have been written.
+    unsafe fn set_len(&mut self, len: usize);
+
+    /// Called between array elements and object fields. A sink that
+    /// forwards its output elsewhere may hand over and drop the bytes held.
+    #[inline]
+    fn drain(&mut self) -> Result<(), JsonError> {
+        Ok(())
+    }
+
+    /// Called by `JsonEncoder::finish` to hand over the rest.
+    #[inline]
+    fn finish(&mut self) -> Result<(), JsonError> {
+        Ok(())
+    }
+}
+
+unsafe impl Sink for Vec<u8> {
+    #[inline]
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+
+    #[inline]
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        self
+    }
+
+    #[inline]
+    fn truncate(&mut self, len: usize) {
+        Vec::truncate(self, len);
+    }
+
+    #[inline]
+    fn reserve(&mut self, additional: usize) -> bool {
+        Vec::reserve(self, additional);
+        true
+    }
+
+    #[inline]
+    fn spare_ptr(&mut self) -> *mut u8 {
+        let len = Vec::len(self);
+        unsafe { self.as_mut_ptr().offset(len as isize) }
+    }
+
+    #[inline]
+    unsafe fn set_len(&mut self, len: usize) {
+        Vec::set_len(self, len);
+    }
+}
+
+unsafe impl<S: Sink + ?Sized> Sink for &mut S {
+    #[inline]
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+
+    #[inline]
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        (**self).as_mut_slice()
+    }
+
+    #[inline]
+    fn truncate(&mut self, len: usize) {
+        (**self).truncate(len);
+    }
+
+    #[inline]
+    fn reserve(&mut self, additional: usize) -> bool {
+        (**self).reserve(additional)
+    }
+
+    #[inline]
+    fn spare_ptr(&mut self) -> *mut u8 {
+        (**self).spare_ptr()
+    }
+
+    #[inline]
+    unsafe fn set_len(&mut self, len: usize) {
+        (**self).set_len(len);
+    }
+
+    #[inline]
+    fn drain(&mut self) -> Result<(), JsonError> {
+        (**self).drain()
+    }
+
+    #[inline]
+    fn finish(&mut self) -> Result<(), JsonError> {
+        (**self).finish()
+    }
+}
+
+/// Writes into a preallocated slice. Output that does not fit is dropped
+/// and the encoder records `JsonError::SinkFull`.
+pub struct SliceSink<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> SliceSink<'a> {
+    pub fn new(buf: &'a mut [u8]) -> SliceSink<'a> {
+        SliceSink {buf: buf, len: 0}
+    }
+
+    /// The bytes written so far.
+    pub fn written(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn into_written(self) -> &'a mut [u8] {
+        let SliceSink {buf, len} = self;
+        &mut buf[..len]
+    }
+}
+
+unsafe impl<'a> Sink for SliceSink<'a> {
+    #[inline]
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buf[..self.len]
+    }
+
+    #[inline]
+    fn truncate(&mut self, len: usize) {
+        if len < self.len {
+            self.len = len;
+        }
+    }
+
+    #[inline]
+    fn reserve(&mut self, additional: usize) -> bool {
+        additional <= self.buf.len() - self.len
+    }
+
+    #[inline]
+    fn spare_ptr(&mut self) -> *mut u8 {
+        unsafe { self.buf.as_mut_ptr().offset(self.len as isize) }
+    }
+
+    #[inline]
+    unsafe fn set_len(&mut self, len: usize) {
+        self.len = len;
+    }
+}
+
+/// Writes into an owned `[u8; N]`, e.g. on the stack. Output that does not
+/// fit is dropped and the encoder records `JsonError::SinkFull`.
+pub struct ArraySink<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> ArraySink<N> {
+    pub fn new() -> ArraySink<N> {
+        ArraySink {buf: [0; N], len: 0}
+    }
+
+    /// The bytes written so far.
+    pub fn written(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl<const N: usize> Default for ArraySink<N> {
+    fn default() -> ArraySink<N> {
+        ArraySink::new()
+    }
+}
+
+unsafe impl<const N: usize> Sink for ArraySink<N> {
+    #[inline]
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buf[..self.len]
+    }
+
+    #[inline]
+    fn truncate(&mut self, len: usize) {
+        if len < self.len {
+            self.len = len;
+        }
+    }
+
+    #[inline]
+    fn reserve(&mut self, additional: usize) -> bool {
+        additional <= N - self.len
+    }
+
+    #[inline]
+    fn spare_ptr(&mut self) -> *mut u8 {
+        unsafe { self.buf.as_mut_ptr().offset(self.len as isize) }
+    }
+
+    #[inline]
+    unsafe fn set_len(&mut self, len: usize) {
+        self.len = len;
+    }
+}
+
+/// Streams to an `io::Write`. Output is collected in a `Vec<u8>` and
+/// written out between array elements and object fields once it exceeds
+/// the high-water mark, so memory stays bounded however large the document
+/// gets. See `JsonEncoder::with_writer`.
+#[cfg(feature = "std")]
+pub struct IoSink<W> {
+    writer: W,
+    data: Vec<u8>,
+    high_water_mark: usize,
+    // the first write error, until `JsonEncoder::flush` hands it out
+    error: Option<io::Error>,
+}
+
+#[cfg(feature = "std")]
+impl<W: io::Write> IoSink<W> {
+    pub fn new(writer: W, high_water_mark: usize) -> IoSink<W> {
+        IoSink {
+            writer: writer,
+            data: Vec::with_capacity(high_water_mark + 64),
+            high_water_mark: high_water_mark,
+            error: None,
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn write_out(&mut self) -> Result<(), JsonError> {
+        let result = match self.error {
+            // the output is broken anyway, only keep memory bounded
+            Some(_) => Ok(()),
+            None => self.writer.write_all(&self.data),
+        };
+        self.data.clear();
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                let kind = err.kind();
+                self.error = Some(err);
+                Err(JsonError::Io(kind))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+unsafe impl<W: io::Write> Sink for IoSink<W> {
+    #[inline]
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    #[inline]
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    #[inline]
+    fn truncate(&mut self, len: usize) {
+        self.data.truncate(len);
+    }
+
+    #[inline]
+    fn reserve(&mut self, additional: usize) -> bool {
+        self.data.reserve(additional);
+        true
+    }
+
+    #[inline]
+    fn spare_ptr(&mut self) -> *mut u8 {
+        self.data.spare_ptr()
+    }
+
+    #[inline]
+    unsafe fn set_len(&mut self, len: usize) {
+        self.data.set_len(len);
+    }
+
+    #[inline]
+    fn drain(&mut self) -> Result<(), JsonError> {
+        if self.data.len() < self.high_water_mark {
+            return Ok(());
+        }
+        self.write_out()
+    }
+
+    fn finish(&mut self) -> Result<(), JsonError> {
+        self.write_out()?;
+        if self.error.is_some() {
+            return Ok(());
+        }
+        self.writer.flush().map_err(|err| {
+            let kind = err.kind();
+            self.error = Some(err);
+            JsonError::Io(kind)
+        })
+    }
+}
+
+/// Writes to a `fmt::Write`, e.g. a `String` or a `fmt::Formatter`.
+/// Output is collected in a `Vec<u8>` and handed over in chunks.
+pub struct FmtSink<W> {
+    writer: W,
+    data: Vec<u8>,
+}
+
+const FMT_CHUNK_SIZE: usize = 4096;
+
+impl<W: fmt::Write> FmtSink<W> {
+    pub fn new(writer: W) -> FmtSink<W> {
+        FmtSink {writer: writer, data: Vec::new()}
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn write_out(&mut self) -> Result<(), JsonError> {
+        let result = match str::from_utf8(&self.data) {
+            Ok(s) => self.writer.write_str(s).map_err(|_| JsonError::Fmt),
+            Err(_) => Err(JsonError::Fmt),
+        };
+        self.data.clear();
+        result
+    }
+}
+
+unsafe impl<W: fmt::Write> Sink for FmtSink<W> {
+    #[inline]
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    #[inline]
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    #[inline]
+    fn truncate(&mut self, len: usize) {
+        self.data.truncate(len);
+    }
+
+    #[inline]
+    fn reserve(&mut self, additional: usize) -> bool {
+        self.data.reserve(additional);
+        true
+    }
+
+    #[inline]
+    fn spare_ptr(&mut self) -> *mut u8 {
+        self.data.spare_ptr()
+    }
+
+    #[inline]
+    unsafe fn set_len(&mut self, len: usize) {
+        self.data.set_len(len);
+    }
+
+    #[inline]
+    fn drain(&mut self) -> Result<(), JsonError> {
+        if self.data.len() < FMT_CHUNK_SIZE {
+            return Ok(());
+        }
+        self.write_out()
+    }
+
+    fn finish(&mut self) -> Result<(), JsonError> {
+        self.write_out()
+    }
+}
+
+#[cfg(feature = "bytes")]
+unsafe impl Sink for ::bytes::BytesMut {
+    #[inline]
+    fn len(&self) -> usize {
+        ::bytes::BytesMut::len(self)
+    }
+
+    #[inline]
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        self
+    }
+
+    #[inline]
+    fn truncate(&mut self, len: usize) {
+        ::bytes::BytesMut::truncate(self, len);
+    }
+
+    #[inline]
+    fn reserve(&mut self, additional: usize) -> bool {
+        ::bytes::BytesMut::reserve(self, additional);
+        true
+    }
+
+    #[inline]
+    fn spare_ptr(&mut self) -> *mut u8 {
+        self.spare_capacity_mut().as_mut_ptr() as *mut u8
+    }
+
+    #[inline]
+    unsafe fn set_len(&mut self, len: usize) {
+        ::bytes::BytesMut::set_len(self, len);
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: io::Write> JsonEncoder<IoSink<W>> {
+    /// Creates an encoder that streams its output to `writer`, see
+    /// `IoSink`. Call `flush` or `into_writer` when done.
+    ///
+    /// Rolling back to a position (`JsonVec::rollback`) that was already
+    /// written out panics.
+    pub fn with_writer(writer: W, high_water_mark: usize) -> JsonEncoder<IoSink<W>> {
+        JsonEncoder::with_sink(IoSink::new(writer, high_water_mark))
+    }
+
+    /// Writes out all pending output and flushes the writer. Returns the
+    /// first write error, or any other error recorded while encoding.
+    ///
+    /// The `io::Error` of the writer is handed out once, as is; later calls
+    /// return a new error of the same kind.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let result = self.buffer.sink.finish();
+        if let Some(err) = self.buffer.sink.error.take() {
+            return Err(err);
+        }
+        match self.error().cloned().or(result.err()) {
+            Some(err) => {
+                let kind = match err {
+                    JsonError::Io(kind) => kind,
+                    _ => io::ErrorKind::InvalidData,
+                };
+                Err(io::Error::new(kind, err))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Flushes and returns the writer.
+    pub fn into_writer(mut self) -> io::Result<W> {
+        self.flush()?;
+        Ok(self.buffer.into_sink().writer)
+    }
+}
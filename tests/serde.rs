@@ -0,0 +1,272 @@
+#![cfg(feature = "serde")]
+
+extern crate json_encoder;
+extern crate serde;
+extern crate serde_json;
+
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+use json_encoder::{JsonEncoder, JsonError, Limits, NonAscii, NonFinite, PrettyConfig};
+use json_encoder::ser::to_vec;
+
+/// Checks that `value` serializes byte-for-byte like `serde_json::to_vec`.
+fn check<T: Serialize + ?Sized>(value: &T) {
+    let expected = serde_json::to_vec(value).unwrap();
+    let actual = to_vec(value).unwrap();
+    assert_eq!(String::from_utf8(expected).unwrap(), String::from_utf8(actual).unwrap());
+}
+
+#[derive(Serialize)]
+struct Unit;
+
+#[derive(Serialize)]
+struct Newtype(i32);
+
+#[derive(Serialize)]
+struct Tuple(u8, &'static str, Option<bool>);
+
+#[derive(Serialize)]
+struct Record {
+    id: u64,
+    name: String,
+    tags: Vec<&'static str>,
+    score: f64,
+    ratio: f32,
+    nested: Option<Box<Record>>,
+    #[serde(rename = "kind")]
+    kind_: Kind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    missing: Option<i8>,
+    #[serde(with = "serde_bytes_as_seq")]
+    raw: Vec<u8>,
+}
+
+mod serde_bytes_as_seq {
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytes)
+    }
+}
+
+#[derive(Serialize)]
+enum Kind {
+    Unit,
+    Newtype(i64),
+    Tuple(i16, u16),
+    Struct { a: char, b: () },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum Internal {
+    A { x: i32 },
+    B,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "t", content = "c")]
+enum Adjacent {
+    A(Vec<u32>),
+    B,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Untagged {
+    A(u32),
+    B { s: String },
+}
+
+#[derive(Serialize)]
+struct Flattened {
+    a: i32,
+    #[serde(flatten)]
+    rest: BTreeMap<String, i32>,
+}
+
+#[test]
+fn test_serde_primitives() {
+    check(&true);
+    check(&false);
+    check(&0i8);
+    check(&i8::MIN);
+    check(&i16::MIN);
+    check(&i32::MIN);
+    check(&i64::MIN);
+    check(&i128::MIN);
+    check(&u8::MAX);
+    check(&u16::MAX);
+    check(&u32::MAX);
+    check(&u64::MAX);
+    check(&u128::MAX);
+    check(&0.0f64);
+    check(&-0.0f64);
+    check(&1.5f64);
+    check(&0.1f32);
+    check(&1e300f64);
+    check(&-1.5e16f64);
+    check(&3e38f32);
+    check(&5e-324f64);
+    check(&f64::NAN);
+    check(&f32::INFINITY);
+    check(&'x');
+    check(&'\u{1F600}');
+    check("");
+    check("plain");
+    check("\"quoted\" \\ \x08\x0c\n\r\t \x00\x01\x1b\x1f\x7f \u{e9}\u{1F600}");
+    check(&());
+    check(&None::<i32>);
+    check(&Some("some"));
+}
+
+#[test]
+fn test_serde_compound() {
+    check(&Unit);
+    check(&Newtype(-7));
+    check(&Tuple(1, "two", None));
+    check(&Vec::<i32>::new());
+    check(&vec![vec![1, 2], vec![]]);
+    check(&(1, "a", [true, false]));
+    check(&Kind::Unit);
+    check(&Kind::Newtype(-1));
+    check(&Kind::Tuple(-2, 3));
+    check(&Kind::Struct { a: '"', b: () });
+    check(&vec![Internal::A { x: 1 }, Internal::B]);
+    check(&vec![Adjacent::A(vec![1]), Adjacent::B]);
+    check(&vec![Untagged::A(1), Untagged::B { s: "s".to_string() }]);
+
+    let mut rest = BTreeMap::new();
+    rest.insert("b".to_string(), 2);
+    rest.insert("c\n".to_string(), 3);
+    check(&Flattened { a: 1, rest });
+
+    let record = Record {
+        id: 1 << 40,
+        name: "name".to_string(),
+        tags: vec!["a", "b\"c"],
+        score: -12.375,
+        ratio: 0.3,
+        nested: Some(Box::new(Record {
+            id: 2,
+            name: String::new(),
+            tags: vec![],
+            score: 0.0,
+            ratio: f32::NAN,
+            nested: None,
+            kind_: Kind::Unit,
+            missing: Some(-1),
+            raw: vec![],
+        })),
+        kind_: Kind::Struct { a: 'a', b: () },
+        missing: None,
+        raw: vec![0, 255],
+    };
+    check(&record);
+}
+
+#[test]
+fn test_serde_map_keys() {
+    let mut map = BTreeMap::new();
+    map.insert("x\"y", 1);
+    map.insert("", 2);
+    check(&map);
+
+    let mut map = BTreeMap::new();
+    map.insert(-5i64, "neg");
+    map.insert(10, "pos");
+    check(&map);
+
+    let mut map = BTreeMap::new();
+    map.insert('c', 1u8);
+    check(&map);
+
+    let mut map = BTreeMap::new();
+    map.insert(true, ());
+    check(&map);
+
+    let mut map = HashMap::new();
+    map.insert(1.5f64.to_bits(), vec![Some(1)]);
+    check(&map);
+
+    let mut map = BTreeMap::new();
+    map.insert(vec![1], 1);
+    assert_eq!(Err(JsonError::KeyMustBeAString), to_vec(&map));
+}
+
+#[test]
+fn test_serde_non_finite_error() {
+    let mut js = JsonEncoder::new();
+    js.set_non_finite(NonFinite::Error);
+    assert_eq!(Err(JsonError::NonFiniteFloat), vec![1.0, f64::NAN].serialize(&mut js));
+
+    let mut js = JsonEncoder::new();
+    js.set_non_finite(NonFinite::Str);
+    vec![1.0, f64::NAN].serialize(&mut js).unwrap();
+    assert_eq!(&b"[1.0,\"NaN\"]"[..], &js.finish().unwrap()[..]);
+}
+
+#[test]
+fn test_serde_pretty() {
+    let mut map = BTreeMap::new();
+    map.insert("k", vec![Some(Kind::Tuple(1, 2)), None]);
+    map.insert("e", vec![]);
+    let value = (Kind::Struct { a: 'a', b: () }, Kind::Newtype(3), Internal::A { x: 1 }, map, Unit);
+
+    let expected = serde_json::to_vec_pretty(&value).unwrap();
+    let mut js = JsonEncoder::new();
+    js.set_pretty(Some(PrettyConfig::default()));
+    value.serialize(&mut js).unwrap();
+    assert_eq!(String::from_utf8(expected).unwrap(), String::from_utf8(js.finish().unwrap()).unwrap());
+}
+
+#[test]
+fn test_serde_limits() {
+    // serializes a nesting of `depth` arrays, counting the levels visited
+    struct Deep<'a>(usize, &'a std::cell::Cell<usize>);
+
+    impl<'a> Serialize for Deep<'a> {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            use serde::ser::SerializeSeq;
+            self.1.set(self.1.get() + 1);
+            let mut seq = serializer.serialize_seq(None)?;
+            if self.0 > 1 {
+                seq.serialize_element(&Deep(self.0 - 1, self.1))?;
+            }
+            seq.end()
+        }
+    }
+
+    let visited = std::cell::Cell::new(0);
+    let mut js = JsonEncoder::new();
+    js.set_limits(Limits {max_depth: Some(64), ..Limits::default()});
+    assert_eq!(Err(JsonError::DepthLimitExceeded), Deep(100_000, &visited).serialize(&mut js));
+    assert_eq!(65, visited.get());
+
+    let mut js = JsonEncoder::new();
+    js.set_limits(Limits {max_str_len: Some(3), ..Limits::default()});
+    assert_eq!(Err(JsonError::StrTooLong), vec!["abc", "abcd", "ab"].serialize(&mut js));
+
+    let mut js = JsonEncoder::new();
+    js.set_limits(Limits {max_output_len: Some(10), ..Limits::default()});
+    assert_eq!(Err(JsonError::OutputLimitExceeded), vec![1_000_000, 2_000_000].serialize(&mut js));
+}
+
+#[test]
+fn test_serde_non_ascii() {
+    let mut map = BTreeMap::new();
+    map.insert("cl\u{e9}".to_string(), vec![
+        "\u{1f600} \u{7ff}\u{800}\u{ffff}\u{10000}\u{10ffff}".to_string(),
+        "\"\n\u{7f}".to_string(),
+    ]);
+
+    for &policy in [NonAscii::EscapeLower, NonAscii::EscapeUpper].iter() {
+        let mut js = JsonEncoder::new();
+        js.set_non_ascii(policy);
+        map.serialize(&mut js).unwrap();
+        let out = js.finish().unwrap();
+        assert!(out.is_ascii());
+        assert_eq!(map, serde_json::from_slice::<BTreeMap<String, Vec<String>>>(&out).unwrap());
+    }
+}